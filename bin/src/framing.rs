@@ -0,0 +1,216 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Length-prefixed framing of the JSON messages exchanged with the parent
+//! processes.
+//!
+//! A frame is a length header followed by that many bytes. Messages up to
+//! `MAX_FRAME_LENGTH` are sent as a single frame, exactly as before. Larger
+//! messages are split into frames of `MAX_FRAME_LENGTH` bytes, every frame but
+//! the last one has the `CONTINUATION` bit set in its length. A message is
+//! rejected once its frames add up to more than `max_message_length()`.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// The largest frame old peers accept, larger messages are split into frames of
+/// this size.
+pub const MAX_FRAME_LENGTH: u32 = 8 * 1024;
+pub const DEFAULT_MAX_MESSAGE_LENGTH: u32 = 1024 * 1024;
+/// Set in the length of every frame of a message but the last one.
+const CONTINUATION: u32 = 1 << 31;
+
+static MAX_MESSAGE_LENGTH: AtomicU32 = AtomicU32::new(DEFAULT_MAX_MESSAGE_LENGTH);
+
+pub fn max_message_length() -> u32 {
+    MAX_MESSAGE_LENGTH.load(Ordering::Relaxed)
+}
+
+pub fn set_max_message_length(length: u32) {
+    MAX_MESSAGE_LENGTH.store(length.min(!CONTINUATION), Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Header {
+    /// 4-byte big-endian length
+    BigEndian,
+    /// 8 hex digits length
+    Hex,
+}
+
+impl Header {
+    async fn read<R>(self, reader: &mut R) -> Result<u32>
+    where
+        R: AsyncReadExt + Unpin,
+    {
+        match self {
+            Header::BigEndian => {
+                let mut buffer = [0; 4];
+                reader
+                    .read_exact(&mut buffer)
+                    .await
+                    .context("failed to receive header")?;
+                Ok(u32::from_be_bytes(buffer))
+            }
+            Header::Hex => {
+                let mut buffer = [0; 8];
+                reader
+                    .read_exact(&mut buffer)
+                    .await
+                    .context("failed to receive header")?;
+                let header = String::from_utf8_lossy(&buffer);
+                u32::from_str_radix(header.as_ref(), 16)
+                    .with_context(|| format!("invalid header: {}", header))
+            }
+        }
+    }
+
+    async fn write<W>(self, writer: &mut W, header: u32) -> Result<()>
+    where
+        W: AsyncWriteExt + Unpin,
+    {
+        match self {
+            Header::BigEndian => writer.write_all(&header.to_be_bytes()).await,
+            Header::Hex => writer.write_all(format!("{:08x}", header).as_ref()).await,
+        }
+        .context("write answer len")
+    }
+}
+
+pub async fn read_message<R>(reader: &mut R, header: Header) -> Result<Vec<u8>>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let max = max_message_length() as usize;
+    let mut message = Vec::new();
+    loop {
+        let length = header.read(reader).await?;
+        let more = length & CONTINUATION != 0;
+        let length = (length & !CONTINUATION) as usize;
+        if message.len() + length > max {
+            return Err(anyhow!("json too large: {}", message.len() + length));
+        }
+        let start = message.len();
+        message.resize(start + length, 0);
+        reader
+            .read_exact(&mut message[start..])
+            .await
+            .context("failed to receive json")?;
+        if !more {
+            return Ok(message);
+        }
+    }
+}
+
+pub async fn write_message<W>(writer: &mut W, header: Header, message: &[u8]) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let mut frames = message.chunks(MAX_FRAME_LENGTH as usize).peekable();
+    if frames.peek().is_none() {
+        header.write(writer, 0).await?;
+    }
+    while let Some(frame) = frames.next() {
+        let mut length = frame.len() as u32;
+        if frames.peek().is_some() {
+            length |= CONTINUATION;
+        }
+        header.write(writer, length).await?;
+        writer.write_all(frame).await.context("write answer")?;
+    }
+    writer.flush().await.context("flush answer")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::block_on;
+
+    #[test]
+    fn test_single_frame_compatible() {
+        block_on(async {
+            for header in [Header::BigEndian, Header::Hex] {
+                let mut buffer = Vec::new();
+                write_message(&mut buffer, header, b"{}").await.unwrap();
+                match header {
+                    Header::BigEndian => assert_eq!(buffer, b"\x00\x00\x00\x02{}"),
+                    Header::Hex => assert_eq!(buffer, b"00000002{}"),
+                }
+                let message = read_message(&mut buffer.as_slice(), header).await.unwrap();
+                assert_eq!(message, b"{}");
+            }
+        });
+    }
+
+    #[test]
+    fn test_continuation_frames() {
+        block_on(async {
+            let json = vec![b'a'; MAX_FRAME_LENGTH as usize * 2 + 1];
+            for header in [Header::BigEndian, Header::Hex] {
+                let mut buffer = Vec::new();
+                write_message(&mut buffer, header, &json).await.unwrap();
+                let message = read_message(&mut buffer.as_slice(), header).await.unwrap();
+                assert_eq!(message, json);
+            }
+
+            let mut buffer = Vec::new();
+            write_message(&mut buffer, Header::BigEndian, &json)
+                .await
+                .unwrap();
+            assert_eq!(buffer[..4], (MAX_FRAME_LENGTH | CONTINUATION).to_be_bytes());
+
+            let mut buffer = Vec::new();
+            write_message(&mut buffer, Header::BigEndian, b"")
+                .await
+                .unwrap();
+            assert_eq!(buffer, [0; 4]);
+        });
+    }
+
+    #[test]
+    fn test_compatible_with_parent() {
+        // the Go side checks the same frames in libcs/util/stdio_test.go
+        let frames = include_bytes!("../../libcs/util/testdata/frames.bin");
+        let json = format!(r#"{{"offerSDP":"{}"}}"#, "0123456789".repeat(2000));
+        block_on(async {
+            let message = read_message(&mut frames.as_slice(), Header::BigEndian)
+                .await
+                .unwrap();
+            assert_eq!(message, json.as_bytes());
+
+            let mut buffer = Vec::new();
+            write_message(&mut buffer, Header::BigEndian, json.as_bytes())
+                .await
+                .unwrap();
+            assert_eq!(buffer, frames);
+        });
+    }
+
+    #[test]
+    fn test_message_too_large() {
+        block_on(async {
+            let mut buffer = Vec::new();
+            let length = DEFAULT_MAX_MESSAGE_LENGTH + 1;
+            buffer.extend_from_slice(&length.to_be_bytes());
+            buffer.resize(buffer.len() + length as usize, b'a');
+            let result = read_message(&mut buffer.as_slice(), Header::BigEndian).await;
+            assert!(result.is_err());
+        });
+    }
+}
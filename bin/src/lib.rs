@@ -15,7 +15,10 @@
  */
 
 pub mod cs;
+pub mod framing;
 pub mod manager;
 pub mod peer;
+#[cfg(test)]
+mod test_util;
 
 
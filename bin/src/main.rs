@@ -42,6 +42,9 @@ struct Cli {
     /// Send signal to the running GT processes
     #[arg(short, long, value_enum)]
     signal: Option<Signal>,
+    /// The maximum size in bytes of a JSON message exchanged with the sub processes
+    #[arg(long, global = true)]
+    max_message_length: Option<u32>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -62,6 +65,9 @@ enum Commands {
 fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let cli = Cli::parse();
+    if let Some(length) = cli.max_message_length {
+        framing::set_max_message_length(length);
+    }
     if let Some(signal) = cli.signal {
         if let Err(e) = manager::send_signal(signal) {
             error!("failed to send {signal:?} signal: {:?}", e);
@@ -42,6 +42,8 @@ use tokio::sync::oneshot::{Receiver, Sender};
 use tokio::time::timeout;
 
 use crate::cs::{ClientArgs, ServerArgs};
+use crate::framing;
+use crate::framing::Header;
 
 #[derive(Debug)]
 pub struct ManagerArgs {
@@ -640,27 +642,12 @@ pub enum OP {
     Reconnect,
 }
 
-const MAX_JSON_LENGTH: u32 = 8 * 1024;
-
 async fn read_json<R, T>(reader: &mut R) -> Result<T>
 where
     R: AsyncReadExt + Unpin,
     T: de::DeserializeOwned,
 {
-    let mut buffer = [0; 4];
-    reader
-        .read_exact(&mut buffer)
-        .await
-        .context("failed to receive header")?;
-    let length = u32::from_be_bytes(buffer);
-    if length > MAX_JSON_LENGTH {
-        return Err(anyhow!("json too large: {}", length));
-    }
-    let mut buffer = vec![0; length as usize];
-    reader
-        .read_exact(&mut buffer)
-        .await
-        .context("failed to receive json")?;
+    let buffer = framing::read_message(reader, Header::BigEndian).await?;
     serde_json::from_reader(Cursor::new(buffer)).context("failed to parse op json")
 }
 
@@ -670,17 +657,7 @@ where
     T: ser::Serialize,
 {
     let json = serde_json::to_string(op)?;
-    let l = json.len() as u32;
-    writer
-        .write_all(&l.to_be_bytes())
-        .await
-        .context("write answer len")?;
-    writer
-        .write_all(json.as_bytes())
-        .await
-        .context("write answer")?;
-    writer.flush().await.context("flush answer")?;
-    Ok(())
+    framing::write_message(writer, Header::BigEndian, json.as_bytes()).await
 }
 
 async fn read_hex_len_json<R, T>(reader: &mut R) -> Result<T>
@@ -688,20 +665,7 @@ where
     R: AsyncReadExt + Unpin,
     T: de::DeserializeOwned,
 {
-    let mut buffer = [0; 8];
-    reader
-        .read_exact(&mut buffer)
-        .await
-        .context("failed to receive header")?;
-    let length = u32::from_str_radix(String::from_utf8_lossy(&buffer).as_ref(), 16)?;
-    if length > MAX_JSON_LENGTH {
-        return Err(anyhow!("json too large: {}", length));
-    }
-    let mut buffer = vec![0; length as usize];
-    reader
-        .read_exact(&mut buffer)
-        .await
-        .context("failed to receive json")?;
+    let buffer = framing::read_message(reader, Header::Hex).await?;
     serde_json::from_reader(Cursor::new(buffer)).context("failed to parse op json")
 }
 
@@ -711,17 +675,7 @@ where
     T: ser::Serialize,
 {
     let json = serde_json::to_string(op)?;
-    let l = json.len() as u32;
-    writer
-        .write_all(format!("{:08x}", l).as_ref())
-        .await
-        .context("write answer len")?;
-    writer
-        .write_all(json.as_bytes())
-        .await
-        .context("write answer")?;
-    writer.flush().await.context("flush answer")?;
-    Ok(())
+    framing::write_message(writer, Header::Hex, json.as_bytes()).await
 }
 
 trait SendShutdownCallback<'a>: Fn(&'a ProcessConfigEnum, &'a mut Cmd) -> Self::Fut {
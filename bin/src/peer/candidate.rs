@@ -0,0 +1,199 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::IpAddr;
+
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::ice::mdns::MulticastDnsMode;
+use webrtc::ice::network_type::NetworkType as IceNetworkType;
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+
+use crate::peer::{Config, MdnsMode, Nat1To1CandidateType, NetworkType};
+
+/// Applies the candidate policy of the config to the setting engine.
+pub(crate) fn apply(config: &Config, s: &mut SettingEngine) -> Result<()> {
+    if !config.network_types.is_empty() {
+        s.set_network_types(
+            config
+                .network_types
+                .iter()
+                .map(|t| match t {
+                    NetworkType::Udp4 => Ok(IceNetworkType::Udp4),
+                    NetworkType::Udp6 => Ok(IceNetworkType::Udp6),
+                    // webrtc-ice gathers no tcp candidates, the peer would be unreachable
+                    NetworkType::Tcp4 | NetworkType::Tcp6 => {
+                        bail!("ICE-TCP is not supported by this build, fall back to a relay")
+                    }
+                })
+                .collect::<Result<_>>()?,
+        );
+    }
+
+    if !config.interface_allow.is_empty() || !config.interface_deny.is_empty() {
+        let allow = config.interface_allow.clone();
+        let deny = config.interface_deny.clone();
+        s.set_interface_filter(Box::new(move |name| interface_allowed(&allow, &deny, name)));
+    }
+
+    if !config.ip_include.is_empty() || !config.ip_exclude.is_empty() {
+        let include = parse_cidrs(&config.ip_include)?;
+        let exclude = parse_cidrs(&config.ip_exclude)?;
+        s.set_ip_filter(Box::new(move |ip| ip_allowed(&include, &exclude, ip)));
+    }
+
+    if !config.nat_1to1_ips.is_empty() {
+        for mapping in &config.nat_1to1_ips {
+            validate_nat_1to1_ip(mapping)?;
+        }
+        let candidate_type = match config.nat_1to1_candidate_type {
+            Nat1To1CandidateType::Host => RTCIceCandidateType::Host,
+            Nat1To1CandidateType::Srflx => RTCIceCandidateType::Srflx,
+        };
+        s.set_nat_1to1_ips(config.nat_1to1_ips.clone(), candidate_type);
+    }
+
+    if let Some(mode) = config.mdns_mode {
+        s.set_ice_multicast_dns_mode(match mode {
+            MdnsMode::Disabled => MulticastDnsMode::Disabled,
+            MdnsMode::QueryOnly => MulticastDnsMode::QueryOnly,
+            MdnsMode::QueryAndGather => MulticastDnsMode::QueryAndGather,
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_cidrs(cidrs: &[String]) -> Result<Vec<IpNet>> {
+    cidrs
+        .iter()
+        .map(|cidr| {
+            cidr.parse::<IpNet>()
+                .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("invalid cidr: {}", cidr))
+        })
+        .collect()
+}
+
+fn validate_nat_1to1_ip(mapping: &str) -> Result<()> {
+    let (public, private) = match mapping.split_once('/') {
+        Some((public, private)) => (public, Some(private)),
+        None => (mapping, None),
+    };
+    let public = public
+        .parse::<IpAddr>()
+        .with_context(|| format!("invalid nat 1:1 public ip: {}", mapping))?;
+    if let Some(private) = private {
+        let private = private
+            .parse::<IpAddr>()
+            .with_context(|| format!("invalid nat 1:1 private ip: {}", mapping))?;
+        if public.is_ipv4() != private.is_ipv4() {
+            bail!("nat 1:1 ip family mismatch: {}", mapping);
+        }
+    }
+    Ok(())
+}
+
+/// An interface is allowed when it matches the allow-list (if any) and does not
+/// match the deny-list. A pattern ending with `*` matches by prefix.
+fn interface_allowed(allow: &[String], deny: &[String], name: &str) -> bool {
+    let matches = |pattern: &String| match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    };
+    (allow.is_empty() || allow.iter().any(matches)) && !deny.iter().any(matches)
+}
+
+fn ip_allowed(include: &[IpNet], exclude: &[IpNet], ip: IpAddr) -> bool {
+    (include.is_empty() || include.iter().any(|net| net.contains(&ip)))
+        && !exclude.iter().any(|net| net.contains(&ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interface_allowed() {
+        let allow = vec!["eth*".to_owned(), "wlan0".to_owned()];
+        let deny = vec!["eth1".to_owned()];
+        assert!(interface_allowed(&allow, &deny, "eth0"));
+        assert!(interface_allowed(&allow, &deny, "wlan0"));
+        assert!(!interface_allowed(&allow, &deny, "eth1"));
+        assert!(!interface_allowed(&allow, &deny, "wlan1"));
+        assert!(!interface_allowed(&allow, &deny, "docker0"));
+
+        let deny = vec!["docker*".to_owned(), "veth*".to_owned()];
+        assert!(interface_allowed(&[], &deny, "eth0"));
+        assert!(!interface_allowed(&[], &deny, "docker0"));
+        assert!(!interface_allowed(&[], &deny, "veth1234"));
+    }
+
+    #[test]
+    fn test_ip_allowed() {
+        let include = parse_cidrs(&["10.0.0.0/8".to_owned(), "2001:db8::/32".to_owned()]).unwrap();
+        let exclude = parse_cidrs(&["10.1.0.0/16".to_owned(), "10.2.3.4".to_owned()]).unwrap();
+        assert!(ip_allowed(&include, &exclude, "10.0.0.1".parse().unwrap()));
+        assert!(ip_allowed(
+            &include,
+            &exclude,
+            "2001:db8::1".parse().unwrap()
+        ));
+        assert!(!ip_allowed(&include, &exclude, "10.1.0.1".parse().unwrap()));
+        assert!(!ip_allowed(&include, &exclude, "10.2.3.4".parse().unwrap()));
+        assert!(!ip_allowed(
+            &include,
+            &exclude,
+            "192.168.1.1".parse().unwrap()
+        ));
+
+        let exclude = parse_cidrs(&["fe80::/10".to_owned()]).unwrap();
+        assert!(ip_allowed(&[], &exclude, "192.168.1.1".parse().unwrap()));
+        assert!(!ip_allowed(&[], &exclude, "fe80::1".parse().unwrap()));
+
+        assert!(parse_cidrs(&["10.0.0.0/33".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_tcp_network_types() {
+        for network_types in [
+            vec![NetworkType::Tcp4],
+            vec![NetworkType::Udp4, NetworkType::Tcp6],
+        ] {
+            let config = Config {
+                network_types,
+                ..Default::default()
+            };
+            let err = apply(&config, &mut SettingEngine::default()).unwrap_err();
+            assert!(err.to_string().contains("ICE-TCP"), "{}", err);
+        }
+        let config = Config {
+            network_types: vec![NetworkType::Udp4, NetworkType::Udp6],
+            ..Default::default()
+        };
+        assert!(apply(&config, &mut SettingEngine::default()).is_ok());
+    }
+
+    #[test]
+    fn test_validate_nat_1to1_ip() {
+        assert!(validate_nat_1to1_ip("203.0.113.1").is_ok());
+        assert!(validate_nat_1to1_ip("203.0.113.1/10.0.0.1").is_ok());
+        assert!(validate_nat_1to1_ip("2001:db8::1/fd00::1").is_ok());
+        assert!(validate_nat_1to1_ip("203.0.113.1/fd00::1").is_err());
+        assert!(validate_nat_1to1_ip("example.com").is_err());
+        assert!(validate_nat_1to1_ip("203.0.113.1/").is_err());
+    }
+}
@@ -17,77 +17,140 @@
 use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
 use log::*;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tokio::time::timeout;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
 use tokio::{io, select, time};
+use tokio_rustls::TlsConnector;
 use url::Url;
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::APIBuilder;
-use webrtc::data::data_channel::PollDataChannel;
+use webrtc::data::data_channel::{DataChannel, PollDataChannel};
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
 use webrtc::data_channel::RTCDataChannel;
 use webrtc::ice::udp_network;
 use webrtc::ice::udp_network::UDPNetwork;
 use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
 use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
 use webrtc::peer_connection::RTCPeerConnection;
 
-use crate::peer::{read_json, write_json, LibError, OP};
+use crate::peer::dns::Resolver;
+use crate::peer::label::{kind_of, strip_params, Label};
+use crate::peer::route::{Route, RouteKind, RouteOptions, Routes};
+use crate::peer::{
+    candidate, connect, error_op, files, http, mux, proxy, proxy_protocol, stats, tls, Config,
+    ConnectOptions, ErrorCode, LibError, OpWriter, ProxyProtocol, OP,
+};
 
-pub(crate) struct PeerConnHandler<R, W> {
-    http_routes: HashMap<String, String>,
-    tcp_routes: HashMap<String, String>,
-    reader: Arc<Mutex<R>>,
-    writer: Arc<Mutex<W>>,
+const MAX_UDP_DATAGRAM_SIZE: usize = 64 * 1024;
+/// The size of the messages an http error response is sent in
+const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+/// How long an http error response may take to be sent before the channel is closed
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// Seconds a disconnected peer connection takes to fail, the default of webrtc-ice
+const ICE_FAILED_TIMEOUT: u16 = 25;
+
+pub(crate) struct PeerConnHandler<W> {
+    routes: RwLock<Routes>,
+    /// Connect options of routes without their own, kept for `OP::UpdateRoutes`
+    connect_options: ConnectOptions,
+    /// Open data channels by id with the target they are connected to
+    channels: Mutex<HashMap<u16, (Arc<RTCDataChannel>, String)>>,
+    tls_connector: Option<TlsConnector>,
+    /// The template of http error responses
+    http_error_page: Option<String>,
+    resolver: Resolver,
+    /// What `socks5://` and `http-connect://` targets may connect to
+    proxy_allow: proxy::AllowList,
+    writer: OpWriter<W>,
     channel_count: AtomicUsize,
     no_channel_id: AtomicUsize,
     peer_connection: Arc<RTCPeerConnection>,
     timeout: u16,
+    ice_restart_timeout: u16,
+    non_trickle: bool,
+    gathering_timeout: u16,
+    /// Whether gathered candidates are sent as `Candidate` ops, off while a
+    /// non-trickle offer/answer is waiting for gathering to complete
+    trickling: Arc<AtomicBool>,
 }
 
-impl<R, W> PeerConnHandler<R, W>
+impl<W> PeerConnHandler<W>
 where
-    R: AsyncReadExt + Unpin + Send + 'static,
     W: AsyncWriteExt + Unpin + Send + 'static,
 {
-    pub async fn new(reader: R, writer: W) -> Result<Arc<Self>> {
-        let reader = Arc::new(Mutex::new(reader));
-        let writer = Arc::new(Mutex::new(writer));
-        let json = timeout(Duration::from_secs(5), read_json(Arc::clone(&reader)))
-            .await
-            .context("read config json timeout")?
-            .context("read config json")?;
-        debug!("config json: {}", &json);
-        let op = serde_json::from_str::<OP>(&json)
-            .with_context(|| format!("deserialize config json failed: {}", json))?;
-        let config = match op {
-            OP::Config(config) => config,
-            _ => {
-                bail!("invalid config json {}", &json);
+    pub async fn new(config: Config, writer: OpWriter<W>) -> Result<Arc<Self>> {
+        if config.ice_restart_timeout >= ICE_FAILED_TIMEOUT {
+            bail!(
+                "ice restart timeout {} is not below the ice failed timeout {}",
+                config.ice_restart_timeout,
+                ICE_FAILED_TIMEOUT
+            );
+        }
+        // the restart offer is sent unasked, only parents expecting it get one
+        let ice_restart_timeout = if writer.supports("ice-restart") {
+            config.ice_restart_timeout
+        } else {
+            if config.ice_restart_timeout > 0 {
+                warn!("the parent did not say hello with ice-restart, no automatic ice restart");
             }
+            0
+        };
+        let ice_transport_policy = if config.relay_only {
+            RTCIceTransportPolicy::Relay
+        } else {
+            RTCIceTransportPolicy::All
         };
-
         let rtc_config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                urls: config.stuns,
-                ..Default::default()
-            }],
+            ice_servers: ice_servers(&config).context("invalid ice servers")?,
+            ice_transport_policy,
             ..Default::default()
         };
 
+        let tls_connector = tls::connector(&config).context("create tls connector")?;
+        let resolver = Resolver::new(&config).context("create resolver")?;
+        let proxy_allow = proxy::AllowList::new(&config).context("invalid proxy allow-list")?;
+        let http_error_page = if config.http_error_page.is_empty() {
+            None
+        } else {
+            let page = tokio::fs::read_to_string(&config.http_error_page)
+                .await
+                .with_context(|| format!("read http error page {}", config.http_error_page))?;
+            Some(page)
+        };
+        let connect_options = config.connect_options.clone();
+        let options = RouteOptions {
+            default: config.connect_options,
+            http: config.http_route_options,
+            tcp: config.tcp_route_options,
+            udp: config.udp_route_options,
+        };
+        let routes = Routes::new(
+            config.http_routes,
+            config.tcp_routes,
+            config.udp_routes,
+            options,
+        )
+        .context(LibError::InvalidRoutes)?;
+
         let mut m = MediaEngine::default();
         m.register_default_codecs()
             .context("register default codecs")?;
@@ -98,10 +161,19 @@ where
             .context("register default interceptors")?;
 
         let mut s = SettingEngine::default();
-        s.set_udp_network(UDPNetwork::Ephemeral(
-            udp_network::EphemeralUDP::new(config.port_min, config.port_max)
-                .context("create udp network")?,
-        ));
+        if config.udp_mux_ports.is_empty() {
+            s.set_udp_network(UDPNetwork::Ephemeral(
+                udp_network::EphemeralUDP::new(config.port_min, config.port_max)
+                    .context("create udp network")?,
+            ));
+        } else if writer.in_session() {
+            s.set_udp_network(UDPNetwork::Muxed(
+                mux::get(&config.udp_mux_ports).context("create udp mux")?,
+            ));
+        } else {
+            bail!("udp mux ports need session mode, the muxes are not shared across processes");
+        }
+        candidate::apply(&config, &mut s).context("apply candidate policy")?;
         s.detach_data_channels();
 
         let api = APIBuilder::new()
@@ -117,23 +189,41 @@ where
         );
 
         let timeout = config.timeout.max(5);
+        let gathering_timeout = if config.gathering_timeout == 0 {
+            5
+        } else {
+            config.gathering_timeout
+        };
         Ok(Arc::new(PeerConnHandler {
-            reader,
             writer,
             peer_connection,
             timeout,
-            http_routes: config.http_routes,
-            tcp_routes: config.tcp_routes,
+            ice_restart_timeout,
+            non_trickle: config.non_trickle,
+            gathering_timeout,
+            trickling: Arc::new(AtomicBool::new(!config.non_trickle)),
+            routes: RwLock::new(routes),
+            connect_options,
+            channels: Default::default(),
+            tls_connector,
+            http_error_page,
+            resolver,
+            proxy_allow,
             channel_count: Default::default(),
             no_channel_id: Default::default(),
         }))
     }
 
-    fn setup_data_channel(self: Arc<Self>, d: Arc<RTCDataChannel>) {
+    fn setup_data_channel(self: &Arc<Self>, d: Arc<RTCDataChannel>) {
+        let handler = Arc::downgrade(self);
         let dc = Arc::clone(&d);
-        d.on_open(Box::new(|| {
-            self.channel_count.fetch_add(1, Ordering::Relaxed);
-            self.new_data_channel_process_handler(dc)
+        d.on_open(Box::new(move || {
+            Box::pin(async move {
+                if let Some(handler) = handler.upgrade() {
+                    handler.channel_count.fetch_add(1, Ordering::Relaxed);
+                    handler.new_data_channel_process_handler(dc).await;
+                }
+            })
         }));
     }
 
@@ -142,38 +232,58 @@ where
         d: Arc<RTCDataChannel>,
     ) -> Pin<Box<impl Future<Output = ()> + Sized>> {
         Box::pin(async move {
-            let label = d.label();
+            // the params are for the sidecar, channels are known without them
+            let label = strip_params(d.label());
             info!("data channel '{}'-'{}' open.", label, d.id());
-            let target = label.split_once('/').map_or_else(
-                || self.http_routes.get("@"),
-                |(t, _)| {
-                    t.get(0..1).map_or_else(
-                        || self.http_routes.get("@"),
-                        |c| {
-                            t.get(1..).map_or_else(
-                                || self.http_routes.get(t),
-                                |r| {
-                                    if c == "@" && !r.is_empty() {
-                                        self.http_routes.get(r)
-                                    } else if c == ":" && !r.is_empty() {
-                                        self.tcp_routes.get(r)
-                                    } else {
-                                        self.http_routes.get(t)
-                                    }
-                                },
-                            )
-                        },
-                    )
-                },
-            );
-            if let Some(target) = target {
-                info!("{} connect to {}", label, target);
-                let dc = Arc::clone(&d);
-                if let Err(err) = self.connect_target(target, dc).await {
-                    info!("{} failed to connect to {}: {}", label, target, err);
+            let route = d
+                .label()
+                .parse::<Label>()
+                .context(LibError::InvalidLabel(label.to_owned()))
+                .and_then(|parsed| {
+                    let route = self.routes.read().unwrap().route(&parsed);
+                    let route = route.ok_or_else(|| LibError::NoRoute(label.to_owned()))?;
+                    Ok((parsed, route))
+                });
+            match &route {
+                Ok((parsed, route)) => {
+                    info!(
+                        "{} connect to {} (channel {}, proxy protocol {:?})",
+                        label, route.target, parsed.id, route.options.proxy_protocol
+                    );
+                    self.channels
+                        .lock()
+                        .unwrap()
+                        .insert(d.id(), (Arc::clone(&d), route.target.clone()));
+                    let dc = Arc::clone(&d);
+                    let result = match parsed.kind {
+                        RouteKind::Udp => self.connect_udp_target(route, dc).await,
+                        RouteKind::Http | RouteKind::Tcp => {
+                            self.connect_target(parsed.kind, route, dc).await
+                        }
+                    };
+                    self.channels.lock().unwrap().remove(&d.id());
+                    if let Err(err) = result {
+                        info!("{} failed to connect to {}: {}", label, route.target, err);
+                        self.write_error(&err, label).await;
+                        if parsed.kind == RouteKind::Http {
+                            self.write_http_error(&d, &err).await;
+                        }
+                        if let Err(e) = d.close().await {
+                            error!("failed to close data channel {}: {}", label, e);
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("{} is not routed: {:#}", label, err);
+                    self.write_error(err, label).await;
+                    // labels that do not parse are answered by the kind they ask for
+                    if kind_of(label) == RouteKind::Http {
+                        self.write_http_error(&d, err).await;
+                    }
+                    if let Err(e) = d.close().await {
+                        error!("failed to close data channel {}: {}", label, e);
+                    }
                 }
-            } else {
-                error!("no routes for {}", label);
             }
             info!("data channel '{}'-'{}' done.", label, d.id());
             let _ = self
@@ -187,41 +297,436 @@ where
         })
     }
 
-    async fn connect_target(&self, target: &str, d: Arc<RTCDataChannel>) -> Result<()> {
+    async fn write_error(&self, err: &anyhow::Error, channel: &str) {
+        if !self.writer.reports_errors() {
+            return;
+        }
+        if let Err(e) = self.writer.write(error_op(err, channel)).await {
+            error!("failed to write error: {}", e);
+        }
+    }
+
+    /// Answers the request of an http route channel that failed before reaching
+    /// its target with an error response, returns whether one was sent.
+    async fn write_http_error(&self, d: &RTCDataChannel, err: &anyhow::Error) -> bool {
+        let Some(status) = http::error_status(err) else {
+            return false;
+        };
+        let response = http::error_response(status, self.http_error_page.as_deref());
+        for message in response.chunks(MAX_MESSAGE_SIZE) {
+            if let Err(e) = d.send(&Bytes::copy_from_slice(message)).await {
+                error!(
+                    "failed to send http error response to {}: {}",
+                    strip_params(d.label()),
+                    e
+                );
+                return false;
+            }
+        }
+        // the channel is closed right after, let the response go first
+        let flushed = time::timeout(FLUSH_TIMEOUT, async {
+            while d.buffered_amount().await > 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        if flushed.await.is_err() {
+            warn!(
+                "http error response to {} not flushed",
+                strip_params(d.label())
+            );
+        }
+        true
+    }
+
+    /// Swaps the route tables, the open data channels keep their targets unless
+    /// `close_removed` is set and their label no longer routes to the same target.
+    async fn update_routes(&self, routes: Routes, close_removed: bool) {
+        *self.routes.write().unwrap() = routes;
+        info!("routes updated");
+        if !close_removed {
+            return;
+        }
+        let stale = {
+            let routes = self.routes.read().unwrap();
+            self.channels
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|(d, target)| {
+                    let label = d.label().parse::<Label>().ok();
+                    label
+                        .and_then(|l| routes.route(&l))
+                        .map(|r| r.target)
+                        .as_ref()
+                        != Some(target)
+                })
+                .map(|(d, _)| Arc::clone(d))
+                .collect::<Vec<_>>()
+        };
+        for d in stale {
+            info!(
+                "close data channel {} as its route was removed",
+                strip_params(d.label())
+            );
+            if let Err(e) = d.close().await {
+                error!(
+                    "failed to close data channel {}: {}",
+                    strip_params(d.label()),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Sends an offer with new ICE credentials, the data channels survive as
+    /// the SCTP association is kept.
+    async fn restart_ice(&self) -> Result<()> {
+        let pc = &self.peer_connection;
+        if pc.signaling_state() != RTCSignalingState::Stable {
+            bail!("ice restart during negotiation: {}", pc.signaling_state());
+        }
+        let options = RTCOfferOptions {
+            ice_restart: true,
+            ..Default::default()
+        };
+        let offer = pc
+            .create_offer(Some(options))
+            .await
+            .context("create ice restart offer")?;
+        self.send_local_description(offer, OP::OfferSDP, self.non_trickle)
+            .await
+    }
+
+    /// Sets the local description and sends it. In trickle mode it is sent
+    /// right away and the candidates follow as `Candidate` ops; otherwise it
+    /// is sent once gathering completes or times out, with the candidates
+    /// gathered so far embedded.
+    async fn send_local_description(
+        &self,
+        desc: RTCSessionDescription,
+        op: fn(String) -> OP,
+        non_trickle: bool,
+    ) -> Result<()> {
+        let pc = &self.peer_connection;
+        self.trickling.store(!non_trickle, Ordering::Relaxed);
+        if !non_trickle {
+            let sdp = serde_json::to_string(&desc).context("serialize sdp")?;
+            self.writer
+                .write(op(sdp))
+                .await
+                .context("write sdp to stdout")?;
+            pc.set_local_description(desc)
+                .await
+                .context("set local description")?;
+            return Ok(());
+        }
+        let mut gathering_complete = pc.gathering_complete_promise().await;
+        pc.set_local_description(desc)
+            .await
+            .context("set local description")?;
+        let gathering_timeout = Duration::from_secs(self.gathering_timeout as u64);
+        if time::timeout(gathering_timeout, gathering_complete.recv())
+            .await
+            .is_err()
+        {
+            warn!("ice gathering timeout, send the candidates gathered so far");
+        }
+        let desc = pc
+            .local_description()
+            .await
+            .ok_or_else(|| anyhow!("no local description"))?;
+        let sdp = serde_json::to_string(&desc).context("serialize sdp")?;
+        self.writer
+            .write(op(sdp))
+            .await
+            .context("write sdp to stdout")
+    }
+
+    async fn connect_target(
+        &self,
+        kind: RouteKind,
+        route: &Route,
+        d: Arc<RTCDataChannel>,
+    ) -> Result<()> {
+        let target = route.target.as_str();
         let url = Url::parse(target).context("invalid url")?;
-        let addrs = url
-            .socket_addrs(|| match url.scheme() {
-                "http" | "ws" | "tcp" => Some(80),
-                "https" | "wss" | "tls" => Some(443),
-                _ => Some(80),
-            })
-            .context("no address")?;
+        if url.scheme() == "file" {
+            return self.serve_files(kind, route, &url, d).await;
+        }
+        if let Some(protocol) = proxy::Protocol::from_scheme(url.scheme()) {
+            return self.serve_proxy(kind, route, protocol, d).await;
+        }
+        let rewrite = self.http_rewrite(kind, route, &url).await;
+        if url.scheme() == "unix" {
+            return self.connect_unix_target(route, &url, d, rewrite).await;
+        }
+        let default_port = if tls::is_tls_scheme(url.scheme()) {
+            443
+        } else {
+            80
+        };
+        let mut s = connect::tcp(&self.resolver, &url, default_port, &route.options)
+            .await
+            .map_err(|e| connect::failed(e, target))?;
+        self.write_proxy_header(route, &d, &mut s).await?;
+
+        // detach once connected, failures before are answered on the channel
+        if let Some(connector) = &self.tls_connector {
+            if tls::is_tls_scheme(url.scheme()) {
+                let mut s = tls::connect(connector, &url, s)
+                    .await
+                    .map_err(|e| connect::failed(e, target))?;
+                let raw = d.detach().await.context("detach data channel")?;
+                return pipe(&d, raw, &mut s, rewrite.as_ref()).await;
+            }
+        }
         let raw = d.detach().await.context("detach data channel")?;
+        pipe(&d, raw, &mut s, rewrite.as_ref()).await
+    }
 
-        let mut s = TcpStream::connect(&*addrs)
+    /// Answers the requests of an http route channel from the files below the
+    /// directory of a `file:///path` target.
+    async fn serve_files(
+        &self,
+        kind: RouteKind,
+        route: &Route,
+        url: &Url,
+        d: Arc<RTCDataChannel>,
+    ) -> Result<()> {
+        if kind != RouteKind::Http {
+            bail!("file targets are only served to http routes: {}", url);
+        }
+        let root = url
+            .to_file_path()
+            .map_err(|_| anyhow!("invalid file url: {}", url))?;
+        let root = tokio::fs::canonicalize(&root)
             .await
-            .context("connect to service")?;
-        let result = io::copy_bidirectional(&mut PollDataChannel::new(raw), &mut s).await;
-        match result {
+            .context(LibError::TargetConnectFailed(url.to_string()))?;
+        if !root.is_dir() {
+            return Err(anyhow!("{} is not a directory", root.display())
+                .context(LibError::TargetConnectFailed(url.to_string())));
+        }
+        let raw = d.detach().await.context("detach data channel")?;
+
+        let mut dc = PollDataChannel::new(raw);
+        match files::serve(&mut dc, &root, route.options.directory_listing).await {
             Ok((a, b)) => {
-                info!("{} copy done: {}, {}", d.label(), a, b);
+                info!("{} serve files done: {}, {}", strip_params(d.label()), a, b);
             }
             Err(err) => {
-                error!("{} copy err: {}", d.label(), err);
+                error!("{} serve files err: {}", strip_params(d.label()), err);
                 bail!(err);
             }
         }
         Ok(())
     }
 
-    pub async fn handle(self: Arc<Self>) -> Result<()> {
-        let writer_on_ice_candidate = Arc::clone(&self.writer);
+    /// Connects a tcp route channel to the target the peer asks for with a
+    /// SOCKS5 or HTTP CONNECT request, if the allow-list has it.
+    async fn serve_proxy(
+        &self,
+        kind: RouteKind,
+        route: &Route,
+        protocol: proxy::Protocol,
+        d: Arc<RTCDataChannel>,
+    ) -> Result<()> {
+        if kind != RouteKind::Tcp {
+            bail!("{} targets are only served to tcp routes", route.target);
+        }
+        let header = self.proxy_header(route, &d).await;
+        let raw = d.detach().await.context("detach data channel")?;
+
+        let mut dc = PollDataChannel::new(raw);
+        let dialer = proxy::Dialer {
+            resolver: &self.resolver,
+            allow: &self.proxy_allow,
+            options: &route.options,
+            header: &header,
+        };
+        match proxy::serve(&mut dc, protocol, &dialer).await {
+            Ok((a, b)) => {
+                info!("{} proxy done: {}, {}", strip_params(d.label()), a, b);
+            }
+            Err(err) => {
+                error!("{} proxy err: {:#}", strip_params(d.label()), err);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// How the requests of an http route are rewritten, `None` for tcp routes
+    /// and http routes bridged as byte pipes. Only plaintext channels are
+    /// rewritten, the peer speaks TLS itself to tls targets unless the sidecar
+    /// originates it.
+    async fn http_rewrite(
+        &self,
+        kind: RouteKind,
+        route: &Route,
+        url: &Url,
+    ) -> Option<http::Rewrite> {
+        if kind != RouteKind::Http {
+            return None;
+        }
+        if tls::is_tls_scheme(url.scheme()) && self.tls_connector.is_none() {
+            if route.options.use_local_as_http_host || route.options.forwarded_headers {
+                warn!("requests to {} are not rewritten, the channel is tls", url);
+            }
+            return None;
+        }
+        let remote = if route.options.forwarded_headers {
+            stats::selected_addresses(&self.peer_connection)
+                .await
+                .map(|(remote, _)| remote.ip())
+        } else {
+            None
+        };
+        http::Rewrite::new(url, &route.options, remote)
+    }
+
+    #[cfg(unix)]
+    async fn connect_unix_target(
+        &self,
+        route: &Route,
+        url: &Url,
+        d: Arc<RTCDataChannel>,
+        rewrite: Option<http::Rewrite>,
+    ) -> Result<()> {
+        let path = url
+            .to_file_path()
+            .map_err(|_| anyhow!("invalid unix socket url: {}", url))?;
+        if path.parent().is_none() {
+            bail!("unix socket path is missing: {}", url);
+        }
+        if !path.exists() {
+            return Err(anyhow!("unix socket {} does not exist", path.display())
+                .context(LibError::TargetConnectFailed(url.to_string())));
+        }
+        let mut s = UnixStream::connect(&path)
+            .await
+            .context(LibError::TargetConnectFailed(url.to_string()))?;
+        self.write_proxy_header(route, &d, &mut s).await?;
+        let raw = d.detach().await.context("detach data channel")?;
+        pipe(&d, raw, &mut s, rewrite.as_ref()).await
+    }
+
+    #[cfg(not(unix))]
+    async fn connect_unix_target(
+        &self,
+        _: &Route,
+        url: &Url,
+        _: Arc<RTCDataChannel>,
+        _: Option<http::Rewrite>,
+    ) -> Result<()> {
+        bail!("unix socket is not supported on this platform: {}", url)
+    }
+
+    /// Sends the PROXY protocol header of the route, if any, ahead of the data
+    /// of the channel.
+    async fn write_proxy_header<S>(
+        &self,
+        route: &Route,
+        d: &RTCDataChannel,
+        s: &mut S,
+    ) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let header = self.proxy_header(route, d).await;
+        if header.is_empty() {
+            return Ok(());
+        }
+        s.write_all(&header)
+            .await
+            .context("write proxy protocol header")
+    }
+
+    /// The PROXY protocol header the route options ask for, empty if none.
+    async fn proxy_header(&self, route: &Route, d: &RTCDataChannel) -> Vec<u8> {
+        if route.options.proxy_protocol == ProxyProtocol::None {
+            return Vec::new();
+        }
+        let addrs = stats::selected_addresses(&self.peer_connection).await;
+        if addrs.is_none() {
+            warn!(
+                "{} proxy protocol header without addresses",
+                strip_params(d.label())
+            );
+        }
+        proxy_protocol::header(route.options.proxy_protocol, addrs, strip_params(d.label()))
+    }
+
+    async fn connect_udp_target(&self, route: &Route, d: Arc<RTCDataChannel>) -> Result<()> {
+        let target = route.target.as_str();
+        if d.ordered() {
+            warn!(
+                "{} udp route over an ordered data channel, datagrams may be delayed",
+                strip_params(d.label())
+            );
+        }
+        let url = Url::parse(target).context("invalid url")?;
+        let addrs = self
+            .resolver
+            .socket_addrs(&url, None)
+            .await
+            .context(LibError::TargetConnectFailed(target.to_owned()))?;
+        let socket = connect::udp(addrs, &route.options)
+            .await
+            .context(LibError::TargetConnectFailed(target.to_owned()))?;
+        let raw = d.detach().await.context("detach data channel")?;
+
+        let mut dc_buf = vec![0; MAX_UDP_DATAGRAM_SIZE];
+        let mut udp_buf = vec![0; MAX_UDP_DATAGRAM_SIZE];
+        let (mut a, mut b) = (0, 0);
+        loop {
+            select! {
+                result = raw.read(&mut dc_buf) => {
+                    let n = result.context("read data channel")?;
+                    if n == 0 {
+                        break;
+                    }
+                    socket.send(&dc_buf[..n]).await.context("send udp datagram")?;
+                    a += n;
+                }
+                result = socket.recv(&mut udp_buf) => {
+                    let n = result.context("recv udp datagram")?;
+                    raw.write(&Bytes::copy_from_slice(&udp_buf[..n]))
+                        .await
+                        .context("write data channel")?;
+                    b += n;
+                }
+            }
+        }
+        info!("{} udp done: {}, {}", strip_params(d.label()), a, b);
+        Ok(())
+    }
+
+    /// Serves the ops of the session until the op channel is closed or the
+    /// peer connection fails, then closes the peer connection.
+    pub async fn handle(self: Arc<Self>, ops: mpsc::Receiver<Result<OP>>) -> Result<()> {
+        let result = Arc::clone(&self).handle_ops(ops).await;
+        if let Err(e) = &result {
+            self.write_error(e, "").await;
+        }
+        if let Err(e) = self.peer_connection.close().await {
+            error!("failed to close peer connection: {}", e);
+        }
+        result
+    }
+
+    async fn handle_ops(self: Arc<Self>, mut ops: mpsc::Receiver<Result<OP>>) -> Result<()> {
+        let writer_on_ice_candidate = self.writer.clone();
+        let trickling = Arc::clone(&self.trickling);
         self.peer_connection
             .on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
                 info!("on_ice_candidate {:?}", c);
-                let writer_on_ice_candidate = Arc::clone(&writer_on_ice_candidate);
+                let writer_on_ice_candidate = writer_on_ice_candidate.clone();
+                let trickling = trickling.load(Ordering::Relaxed);
                 Box::pin(async move {
-                    if let Some(c) = c {
+                    if !trickling {
+                        return;
+                    }
+                    let candidate = if let Some(c) = c {
                         let json = match c.to_json() {
                             Err(e) => {
                                 error!("failed to serialize ice candidate: {}", e);
@@ -229,41 +734,27 @@ where
                             }
                             Ok(json) => json,
                         };
-                        let json = match serde_json::to_string(&json) {
+                        match serde_json::to_string(&json) {
                             Err(e) => {
                                 error!("failed to serialize ice candidate init: {}", e);
                                 return;
                             }
                             Ok(json) => json,
-                        };
-                        let op = OP::Candidate(json);
-                        let json = match serde_json::to_string(&op) {
-                            Err(e) => {
-                                error!("failed to serialize op: {}", e);
-                                return;
-                            }
-                            Ok(json) => json,
-                        };
-                        if let Err(e) = write_json(writer_on_ice_candidate, &json).await {
-                            error!("failed to write ice candidate: {}", e);
                         }
                     } else {
-                        let op = OP::Candidate("".to_owned());
-                        let json = match serde_json::to_string(&op) {
-                            Err(e) => {
-                                error!("failed to serialize op: {}", e);
-                                return;
-                            }
-                            Ok(json) => json,
-                        };
-                        if let Err(e) = write_json(writer_on_ice_candidate, &json).await {
-                            error!("failed to write ice candidate: {}", e);
-                        }
+                        "".to_owned()
+                    };
+                    if let Err(e) = writer_on_ice_candidate
+                        .write(OP::Candidate(candidate))
+                        .await
+                    {
+                        error!("failed to write ice candidate: {}", e);
                     }
                 })
             }));
 
         let (done_tx, mut done_rx) = tokio::sync::mpsc::channel::<Result<()>>(1);
+        let (disconnected_tx, mut disconnected_rx) = mpsc::unbounded_channel::<bool>();
 
         self.peer_connection
             .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
@@ -272,10 +763,14 @@ where
                     RTCPeerConnectionState::Unspecified => {}
                     RTCPeerConnectionState::New => {}
                     RTCPeerConnectionState::Connecting => {}
-                    RTCPeerConnectionState::Connected => {}
-                    RTCPeerConnectionState::Disconnected => {}
+                    RTCPeerConnectionState::Connected => {
+                        let _ = disconnected_tx.send(false);
+                    }
+                    RTCPeerConnectionState::Disconnected => {
+                        let _ = disconnected_tx.send(true);
+                    }
                     RTCPeerConnectionState::Failed => {
-                        let _ = done_tx.try_send(Err(anyhow!("peer connection state failed")));
+                        let _ = done_tx.try_send(Err(LibError::IceFailed.into()));
                     }
                     RTCPeerConnectionState::Closed => {}
                 }
@@ -283,22 +778,29 @@ where
                 Box::pin(async {})
             }));
 
-        let handler = Arc::clone(&self);
+        let handler = Arc::downgrade(&self);
         self.peer_connection
             .on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| {
-                info!("new dataChannel {} {}", d.label(), d.id());
-                let handler = Arc::clone(&handler);
-                handler.setup_data_channel(d);
+                info!("new dataChannel {} {}", strip_params(d.label()), d.id());
+                if let Some(handler) = handler.upgrade() {
+                    handler.setup_data_channel(d);
+                }
                 Box::pin(async {})
             }));
 
         let mut no_channel_id: usize = 0;
+        let mut restart_at: Option<time::Instant> = None;
         loop {
             let sleep = time::sleep(Duration::from_secs(self.timeout as u64));
             tokio::pin!(sleep);
-            let json = select! {
-                result = read_json(Arc::clone(&self.reader)) => {
-                    result?
+            let restart = time::sleep_until(restart_at.unwrap_or_else(time::Instant::now));
+            tokio::pin!(restart);
+            let op = select! {
+                op = ops.recv() => {
+                    match op {
+                        None => return Ok(()),
+                        Some(op) => op?,
+                    }
                 },
                 rx = done_rx.recv() => {
                     return match rx {
@@ -309,6 +811,24 @@ where
                             result
                         }
                     }
+                }
+                Some(disconnected) = disconnected_rx.recv() => {
+                    restart_at = (disconnected && self.ice_restart_timeout > 0).then(|| {
+                        time::Instant::now() + Duration::from_secs(self.ice_restart_timeout as u64)
+                    });
+                    continue;
+                }
+                _ = &mut restart, if restart_at.is_some() => {
+                    restart_at = None;
+                    info!("peer connection disconnected, restart ice");
+                    if let Err(e) = self.restart_ice().await {
+                        error!("failed to restart ice, retry later: {:?}", e);
+                        restart_at = Some(
+                            time::Instant::now()
+                                + Duration::from_secs(self.ice_restart_timeout as u64),
+                        );
+                    }
+                    continue;
                 }
                  _ = &mut sleep => {
                     if self.channel_count.load(Ordering::Acquire) == 0 {
@@ -322,30 +842,22 @@ where
                     continue;
                 }
             };
-            debug!("op json: {}", &json);
-            let op = serde_json::from_str::<OP>(&json)
-                .with_context(|| format!("parse op json: {}", json))?;
+            debug!("op: {:?}", &op);
 
             let pc = Arc::clone(&self.peer_connection);
             match op {
                 OP::OfferSDP(sdp) => {
                     let sdp = serde_json::from_str::<RTCSessionDescription>(&sdp)
-                        .context("offer sdp from op")?;
+                        .context("offer sdp from op")
+                        .context(LibError::InvalidSdp)?;
                     pc.set_remote_description(sdp)
                         .await
-                        .context("set remote description")?;
+                        .context("set remote description")
+                        .context(LibError::InvalidSdp)?;
                     let answer = pc.create_answer(None).await.context("create answer")?;
-                    let sdp = serde_json::to_string(&answer).context("serialize answer")?;
-                    let op = OP::AnswerSDP(sdp);
-                    write_json(
-                        Arc::clone(&self.writer),
-                        &serde_json::to_string(&op).context("encode op")?,
-                    )
-                    .await
-                    .context("write answer sdp to stdout")?;
-                    pc.set_local_description(answer)
+                    self.send_local_description(answer, OP::AnswerSDP, self.non_trickle)
                         .await
-                        .context("set local description")?;
+                        .context("send answer")?;
                 }
                 OP::Candidate(candidate) => {
                     if candidate.is_empty() {
@@ -357,37 +869,149 @@ where
                         .await
                         .context("add candidate")?;
                 }
-                OP::GetOfferSDP { channel_name } => {
+                OP::GetOfferSDP {
+                    channel_name,
+                    non_trickle,
+                } => {
+                    let label = channel_name.parse::<Label>().ok();
+                    let options = if matches!(label, Some(l) if l.kind == RouteKind::Udp) {
+                        Some(RTCDataChannelInit {
+                            ordered: Some(false),
+                            max_retransmits: Some(0),
+                            ..Default::default()
+                        })
+                    } else {
+                        None
+                    };
                     let data_channel = pc
-                        .create_data_channel(&channel_name, None)
+                        .create_data_channel(&channel_name, options)
                         .await
                         .context("create data channel")?;
-                    let handler = Arc::clone(&self);
-                    handler.setup_data_channel(data_channel);
+                    self.setup_data_channel(data_channel);
                     let offer = pc.create_offer(None).await.context("create offer")?;
-                    let sdp = serde_json::to_string(&offer).context("serialize answer")?;
-                    let op = OP::OfferSDP(sdp);
-                    write_json(
-                        Arc::clone(&self.writer),
-                        &serde_json::to_string(&op).context("encode op")?,
-                    )
-                    .await
-                    .context("write answer sdp to stdout")?;
-                    pc.set_local_description(offer)
+                    let non_trickle = non_trickle.unwrap_or(self.non_trickle);
+                    self.send_local_description(offer, OP::OfferSDP, non_trickle)
                         .await
-                        .context("set local description")?;
+                        .context("send offer")?;
                 }
                 OP::AnswerSDP(sdp) => {
                     let sdp = serde_json::from_str::<RTCSessionDescription>(&sdp)
-                        .context("answer sdp from op")?;
+                        .context("answer sdp from op")
+                        .context(LibError::InvalidSdp)?;
                     pc.set_remote_description(sdp)
                         .await
-                        .context("set remote description")?;
+                        .context("set remote description")
+                        .context(LibError::InvalidSdp)?;
+                }
+                OP::RestartIce {} => {
+                    // a restart refused, e.g. during a renegotiation, leaves the
+                    // session as it was
+                    if let Err(e) = self.restart_ice().await {
+                        error!("failed to restart ice: {:#}", e);
+                        self.write_error(&e, "").await;
+                    }
+                }
+                OP::UpdateRoutes {
+                    http_routes,
+                    tcp_routes,
+                    udp_routes,
+                    http_route_options,
+                    tcp_route_options,
+                    udp_route_options,
+                    close_removed,
+                } => {
+                    let options = RouteOptions {
+                        default: self.connect_options.clone(),
+                        http: http_route_options,
+                        tcp: tcp_route_options,
+                        udp: udp_route_options,
+                    };
+                    match Routes::new(http_routes, tcp_routes, udp_routes, options) {
+                        Ok(routes) => self.update_routes(routes, close_removed).await,
+                        Err(e) => {
+                            let err = e.context(LibError::InvalidRoutes);
+                            error!("failed to update routes: {:#}", err);
+                            self.write_error(&err, "").await;
+                        }
+                    }
+                }
+                OP::GetStats {} => {
+                    let stats = stats::collect(&pc).await;
+                    self.writer
+                        .write(OP::Stats(stats))
+                        .await
+                        .context("write stats to stdout")?;
                 }
-                _ => {
-                    bail!("invalid op {:?}", op)
+                op => {
+                    warn!("invalid op {:?}", op);
+                    if self.writer.reports_errors() {
+                        self.writer
+                            .write(OP::Error {
+                                code: ErrorCode::InvalidOp,
+                                message: format!("invalid op {:?}", op),
+                                channel: String::new(),
+                            })
+                            .await
+                            .context("write error to stdout")?;
+                    }
                 }
             };
         }
     }
 }
+
+/// Bridges the data channel and the target stream, parsing the requests on the
+/// channel if they are rewritten.
+async fn pipe<S>(
+    d: &RTCDataChannel,
+    raw: Arc<DataChannel>,
+    s: &mut S,
+    rewrite: Option<&http::Rewrite>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut dc = PollDataChannel::new(raw);
+    let result = match rewrite {
+        Some(rewrite) => http::proxy(&mut dc, s, rewrite).await,
+        None => io::copy_bidirectional(&mut dc, s).await,
+    };
+    match result {
+        Ok((a, b)) => {
+            info!("{} copy done: {}, {}", strip_params(d.label()), a, b);
+        }
+        Err(err) => {
+            error!("{} copy err: {}", strip_params(d.label()), err);
+            bail!(err);
+        }
+    }
+    Ok(())
+}
+
+fn ice_servers(config: &Config) -> Result<Vec<RTCIceServer>> {
+    let mut servers = Vec::with_capacity(config.ice_servers.len() + 1);
+    if !config.stuns.is_empty() {
+        servers.push(RTCIceServer {
+            urls: config.stuns.clone(),
+            ..Default::default()
+        });
+    }
+    for server in &config.ice_servers {
+        let credential_type = match server.credential_type.as_str() {
+            "" | "password" => RTCIceCredentialType::Password,
+            "oauth" => RTCIceCredentialType::Oauth,
+            other => bail!("unknown credential type {}", other),
+        };
+        servers.push(RTCIceServer {
+            urls: server.urls.clone(),
+            username: server.username.clone(),
+            credential: server.credential.clone(),
+            credential_type,
+        });
+    }
+    let turn = |url: &String| url.starts_with("turn:") || url.starts_with("turns:");
+    if config.relay_only && !config.ice_servers.iter().any(|s| s.urls.iter().any(turn)) {
+        bail!("relay only without a turn server");
+    }
+    Ok(servers)
+}
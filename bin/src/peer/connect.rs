@@ -0,0 +1,378 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::*;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::{select, time};
+use url::Url;
+
+use crate::peer::dns::Resolver;
+use crate::peer::{ConnectOptions, LibError, ProxyProtocol};
+
+const DEFAULT_TIMEOUT: u16 = 10;
+const DEFAULT_RETRY_BACKOFF: u32 = 100;
+/// Delay before racing the next address, see RFC 8305 section 5
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+impl ConnectOptions {
+    pub(crate) fn validate(&self) -> Result<()> {
+        self.bind_address()?;
+        if !self.bind_interface.is_empty()
+            && !cfg!(any(
+                target_os = "android",
+                target_os = "fuchsia",
+                target_os = "linux"
+            ))
+        {
+            bail!("bind interface is not supported on this platform");
+        }
+        Ok(())
+    }
+
+    /// Validates the options of a udp route, which may not set the options
+    /// that only apply to tcp connections or http requests.
+    pub(crate) fn validate_udp(&self) -> Result<()> {
+        self.validate()?;
+        if self.timeout > 0
+            || self.retries > 0
+            || self.retry_backoff > 0
+            || self.no_delay
+            || self.keepalive > 0
+        {
+            bail!("timeout, retries, retry backoff, no delay and keepalive only apply to tcp");
+        }
+        if self.proxy_protocol != ProxyProtocol::None {
+            bail!("proxy protocol only applies to tcp and http");
+        }
+        if self.use_local_as_http_host
+            || self.forwarded_headers
+            || !self.forwarded_proto.is_empty()
+            || self.directory_listing
+        {
+            bail!("http host, forwarded headers and directory listing only apply to http");
+        }
+        Ok(())
+    }
+
+    fn bind_address(&self) -> Result<Option<SocketAddr>> {
+        if self.bind_address.is_empty() {
+            return Ok(None);
+        }
+        self.bind_address
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                self.bind_address
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, 0))
+            })
+            .map(Some)
+            .with_context(|| format!("invalid bind address {}", self.bind_address))
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        let timeout = if self.timeout == 0 {
+            DEFAULT_TIMEOUT
+        } else {
+            self.timeout
+        };
+        Duration::from_secs(timeout as u64)
+    }
+
+    fn retry_backoff(&self) -> Duration {
+        let backoff = if self.retry_backoff == 0 {
+            DEFAULT_RETRY_BACKOFF
+        } else {
+            self.retry_backoff
+        };
+        Duration::from_millis(backoff as u64)
+    }
+}
+
+/// Connects to a TCP route target. Every attempt, resolution included, is
+/// bounded by the connect timeout and failed attempts are retried with an
+/// exponential backoff.
+pub(crate) async fn tcp(
+    resolver: &Resolver,
+    url: &Url,
+    default_port: u16,
+    options: &ConnectOptions,
+) -> Result<TcpStream> {
+    let resolve = || resolver.socket_addrs(url, Some(default_port));
+    retry(url.as_str(), options, resolve).await
+}
+
+/// Connects like `tcp` to addresses resolved beforehand, `name` is for logs.
+pub(crate) async fn tcp_addrs(
+    name: &str,
+    addrs: &[SocketAddr],
+    options: &ConnectOptions,
+) -> Result<TcpStream> {
+    retry(name, options, || async { Ok(addrs.to_vec()) }).await
+}
+
+async fn retry<F, R>(name: &str, options: &ConnectOptions, resolve: F) -> Result<TcpStream>
+where
+    F: Fn() -> R,
+    R: Future<Output = Result<Vec<SocketAddr>>>,
+{
+    let bind = options.bind_address()?;
+    let timeout = options.timeout();
+    let mut backoff = options.retry_backoff();
+    let mut retries = 0;
+    loop {
+        let attempt = async {
+            let mut addrs = resolve().await?;
+            if let Some(bind) = bind {
+                addrs.retain(|addr| addr.is_ipv4() == bind.is_ipv4());
+            }
+            happy_eyeballs(addrs, bind, &options.bind_interface).await
+        };
+        let result = match time::timeout(timeout, attempt).await {
+            Ok(result) => result,
+            Err(e) => Err(anyhow!(e).context(format!("connect timeout after {:?}", timeout))),
+        };
+        match result {
+            Ok(stream) => {
+                set_socket_options(&stream, options)?;
+                return Ok(stream);
+            }
+            Err(e) if retries < options.retries => {
+                retries += 1;
+                warn!(
+                    "connect to {} failed, retry {}/{} in {:?}: {:#}",
+                    name, retries, options.retries, backoff, e
+                );
+                time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Marks a failed connection to a route target, as timed out if it did.
+pub(crate) fn failed(err: anyhow::Error, target: &str) -> anyhow::Error {
+    let timed_out = err.chain().any(|e| {
+        e.is::<time::error::Elapsed>()
+            || e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+    });
+    if timed_out {
+        err.context(LibError::TargetConnectTimeout(target.to_owned()))
+    } else {
+        err.context(LibError::TargetConnectFailed(target.to_owned()))
+    }
+}
+
+/// Binds a UDP socket as the options say and connects it to the first address
+/// of a matching family.
+pub(crate) async fn udp(addrs: Vec<SocketAddr>, options: &ConnectOptions) -> Result<UdpSocket> {
+    let bind = options.bind_address()?;
+    let addr = addrs
+        .into_iter()
+        .find(|addr| bind.map_or(true, |bind| addr.is_ipv4() == bind.is_ipv4()))
+        .ok_or_else(|| anyhow!("no address"))?;
+    let bind = bind.unwrap_or_else(|| match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    });
+    let socket = UdpSocket::bind(bind).await.context("bind udp socket")?;
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if !options.bind_interface.is_empty() {
+        socket
+            .bind_device(Some(options.bind_interface.as_bytes()))
+            .context("bind udp socket to interface")?;
+    }
+    socket.connect(addr).await.context("connect udp socket")?;
+    Ok(socket)
+}
+
+fn set_socket_options(stream: &TcpStream, options: &ConnectOptions) -> Result<()> {
+    stream
+        .set_nodelay(options.no_delay)
+        .context("set TCP_NODELAY")?;
+    if options.keepalive > 0 {
+        let interval = Duration::from_secs(options.keepalive as u64);
+        let keepalive = TcpKeepalive::new().with_time(interval);
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+        let keepalive = keepalive.with_interval(interval);
+        SockRef::from(stream)
+            .set_tcp_keepalive(&keepalive)
+            .context("set tcp keepalive")?;
+    }
+    Ok(())
+}
+
+/// Connects to the first address that accepts, RFC 8305 style. The addresses
+/// are tried alternating between IPv6 and IPv4, a new attempt starts as soon
+/// as the previous one fails or after `CONNECTION_ATTEMPT_DELAY`, and the
+/// attempts still running are dropped once one succeeds.
+async fn happy_eyeballs(
+    addrs: Vec<SocketAddr>,
+    bind: Option<SocketAddr>,
+    interface: &str,
+) -> Result<TcpStream> {
+    let mut pending = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = pending.next() {
+            attempts.push(connect(addr, bind, interface));
+        }
+        if attempts.is_empty() {
+            return Err(last_error.unwrap_or_else(|| anyhow!("no address")));
+        }
+        select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("connect to {} failed: {}", addr, e);
+                    last_error = Some(anyhow!(e).context(format!("connect to {}", addr)));
+                }
+            },
+            _ = time::sleep(CONNECTION_ATTEMPT_DELAY), if !pending.as_slice().is_empty() => {}
+        }
+    }
+}
+
+async fn connect(
+    addr: SocketAddr,
+    bind: Option<SocketAddr>,
+    interface: &str,
+) -> (SocketAddr, io::Result<TcpStream>) {
+    let result = async {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if !interface.is_empty() {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+        let _ = interface;
+        if let Some(bind) = bind {
+            socket.bind(bind)?;
+        }
+        socket.connect(addr).await
+    };
+    (addr, result.await)
+}
+
+/// Orders the addresses by alternating families, starting with IPv6.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let len = addrs.len();
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut addrs = Vec::with_capacity(len);
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return addrs,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleave() {
+        let addrs = [
+            "1.1.1.1:80",
+            "2.2.2.2:80",
+            "[::1]:80",
+            "3.3.3.3:80",
+            "[::2]:80",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        let expected = [
+            "[::1]:80",
+            "1.1.1.1:80",
+            "[::2]:80",
+            "2.2.2.2:80",
+            "3.3.3.3:80",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect::<Vec<SocketAddr>>();
+        assert_eq!(interleave(addrs), expected);
+    }
+
+    #[test]
+    fn test_bind_address() {
+        let options = |bind: &str| ConnectOptions {
+            bind_address: bind.to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(options("").bind_address().unwrap(), None);
+        assert_eq!(
+            options("10.0.0.1").bind_address().unwrap(),
+            Some("10.0.0.1:0".parse().unwrap())
+        );
+        assert_eq!(
+            options("[fd00::1]:4000").bind_address().unwrap(),
+            Some("[fd00::1]:4000".parse().unwrap())
+        );
+        assert!(options("eth0").bind_address().is_err());
+    }
+
+    #[test]
+    fn test_validate_udp() {
+        let options = ConnectOptions {
+            bind_address: "10.0.0.1".to_owned(),
+            ..Default::default()
+        };
+        assert!(options.validate_udp().is_ok());
+        for options in [
+            ConnectOptions {
+                keepalive: 30,
+                ..Default::default()
+            },
+            ConnectOptions {
+                proxy_protocol: ProxyProtocol::V1,
+                ..Default::default()
+            },
+            ConnectOptions {
+                use_local_as_http_host: true,
+                ..Default::default()
+            },
+            ConnectOptions {
+                forwarded_headers: true,
+                ..Default::default()
+            },
+            ConnectOptions {
+                directory_listing: true,
+                ..Default::default()
+            },
+        ] {
+            assert!(options.validate_udp().is_err(), "{:?}", options);
+            assert!(options.validate().is_ok(), "{:?}", options);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use tokio::net::lookup_host;
+use tokio::time::Instant;
+use url::{Host, Url};
+
+use crate::peer::Config;
+
+const DEFAULT_CACHE_TTL: u16 = 30;
+
+/// Resolves route target hosts without blocking the runtime. Static hosts of
+/// the config win over DNS, DNS answers are cached for `dns_cache_ttl`. Each
+/// session has its own resolver, sessions never see each other's cache.
+pub(crate) struct Resolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, Vec<IpAddr>)>>,
+}
+
+impl Resolver {
+    pub(crate) fn new(config: &Config) -> Result<Self> {
+        let mut hosts = HashMap::with_capacity(config.hosts.len());
+        for (host, ips) in &config.hosts {
+            let ips = ips
+                .iter()
+                .map(|ip| ip.parse::<IpAddr>())
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("invalid ip of host {}", host))?;
+            hosts.insert(host.to_ascii_lowercase(), ips);
+        }
+        let ttl = if config.dns_cache_ttl == 0 {
+            DEFAULT_CACHE_TTL
+        } else {
+            config.dns_cache_ttl
+        };
+        Ok(Resolver {
+            hosts,
+            ttl: Duration::from_secs(ttl as u64),
+            cache: Default::default(),
+        })
+    }
+
+    /// The socket addresses of the url, `default_port` is used if the url has
+    /// no port and its scheme no known default port.
+    pub(crate) async fn socket_addrs(
+        &self,
+        url: &Url,
+        default_port: Option<u16>,
+    ) -> Result<Vec<SocketAddr>> {
+        let port = url
+            .port_or_known_default()
+            .or(default_port)
+            .ok_or_else(|| anyhow!("no port in {}", url))?;
+        let ips = match url.host() {
+            Some(Host::Domain(domain)) => self.resolve(domain).await?,
+            Some(Host::Ipv4(ip)) => vec![ip.into()],
+            Some(Host::Ipv6(ip)) => vec![ip.into()],
+            None => bail!("no host in {}", url),
+        };
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    pub(crate) async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        // hosts of non-special schemes such as tcp:// are not parsed as IPs by url
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        let host = host.to_ascii_lowercase();
+        if let Some(ips) = self.hosts.get(&host) {
+            return Ok(ips.clone());
+        }
+        if let Some((expires_at, ips)) = self.cache.lock().unwrap().get(&host) {
+            if *expires_at > Instant::now() {
+                return Ok(ips.clone());
+            }
+        }
+        let ips = lookup_host((host.as_str(), 0))
+            .await
+            .with_context(|| format!("resolve {}", host))?
+            .map(|addr| addr.ip())
+            .collect::<Vec<_>>();
+        if ips.is_empty() {
+            bail!("no address of {}", host);
+        }
+        debug!("resolved {} to {:?}", host, ips);
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        cache.retain(|_, (expires_at, _)| *expires_at > now);
+        cache.insert(host, (now + self.ttl, ips.clone()));
+        Ok(ips)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+    use crate::test_util::block_on;
+
+    fn new_resolver(hosts: &[(&str, &[&str])], dns_cache_ttl: u16) -> Result<Resolver> {
+        let config = Config {
+            hosts: hosts
+                .iter()
+                .map(|(host, ips)| {
+                    (
+                        host.to_string(),
+                        ips.iter().map(|ip| ip.to_string()).collect(),
+                    )
+                })
+                .collect(),
+            dns_cache_ttl,
+            ..Default::default()
+        };
+        Resolver::new(&config)
+    }
+
+    #[test]
+    fn test_hosts() {
+        block_on(async {
+            let resolver = new_resolver(&[("LocalHost", &["10.0.0.1", "fd00::1"])], 0).unwrap();
+            let ips: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "fd00::1".parse().unwrap()];
+            assert_eq!(resolver.resolve("localhost").await.unwrap(), ips);
+            assert_eq!(resolver.resolve("LOCALHOST").await.unwrap(), ips);
+            assert!(resolver.cache.lock().unwrap().is_empty());
+
+            let url = Url::parse("tcp://localhost:22").unwrap();
+            let addrs = resolver.socket_addrs(&url, None).await.unwrap();
+            assert_eq!(addrs[0], "10.0.0.1:22".parse().unwrap());
+
+            assert!(new_resolver(&[("localhost", &["10.0.0.256"])], 0).is_err());
+        });
+    }
+
+    #[test]
+    fn test_cache() {
+        block_on(async {
+            time::pause();
+            for (dns_cache_ttl, ttl) in [(0, DEFAULT_CACHE_TTL), (5, 5)] {
+                let resolver = new_resolver(&[], dns_cache_ttl).unwrap();
+                let ips = resolver.resolve("localhost").await.unwrap();
+                // a cache hit answers what the cache holds
+                let cached = vec!["10.9.9.9".parse::<IpAddr>().unwrap()];
+                {
+                    let mut cache = resolver.cache.lock().unwrap();
+                    let (expires_at, ips) = cache.get_mut("localhost").unwrap();
+                    let ttl = Duration::from_secs(ttl as u64);
+                    assert_eq!(*expires_at, Instant::now() + ttl);
+                    *ips = cached.clone();
+                }
+                time::advance(Duration::from_secs(ttl as u64 - 1)).await;
+                assert_eq!(resolver.resolve("localhost").await.unwrap(), cached);
+                time::advance(Duration::from_secs(1)).await;
+                assert_eq!(resolver.resolve("localhost").await.unwrap(), ips);
+            }
+        });
+    }
+}
@@ -0,0 +1,746 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Static files of `file:///path` http routes, served over the data channel.
+//!
+//! Requests are answered one after another with GET and HEAD only. Request
+//! paths are percent-decoded and resolved below the root, `..` segments are
+//! rejected and so are paths whose symlinks lead out of the root. Directories
+//! are served by their `index.html`, or listed if the route allows it.
+
+use std::fmt::Write as _;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::*;
+use tokio::fs::{self, File};
+use tokio::io::{
+    self, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+
+use crate::peer::http::{self, Body, Request};
+
+const INDEX: &str = "index.html";
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Serves the files below `root`, a canonical path, until the channel ends or
+/// a request asks to close it. Returns the bytes read and written like
+/// `io::copy_bidirectional`.
+pub(crate) async fn serve<C>(channel: &mut C, root: &Path, listing: bool) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = io::split(channel);
+    let mut reader = BufReader::new(reader);
+    let (mut read, mut written) = (0, 0);
+    loop {
+        let Some(head) = http::read_head(&mut reader).await? else {
+            break;
+        };
+        read += head.len() as u64;
+        let request = match Request::parse(&head) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("invalid request: {}", e);
+                let response = Response::new(400).close();
+                written += response.write(&mut writer, false).await?;
+                break;
+            }
+        };
+        // bodies mean nothing to static files, skip them
+        read += match request.body() {
+            Ok(Body::None) => 0,
+            Ok(Body::Length(n)) => http::copy_exact(&mut reader, &mut io::sink(), n).await?,
+            Ok(Body::Chunked) => http::copy_chunked(&mut reader, &mut io::sink()).await?,
+            Err(e) => {
+                debug!("invalid request body: {}", e);
+                let response = Response::new(400).close();
+                written += response.write(&mut writer, false).await?;
+                break;
+            }
+        };
+        let keep_alive = request.keep_alive();
+        let mut response = respond(&request, root, listing).await;
+        if !keep_alive {
+            response = response.close();
+        }
+        let head = request.method() == b"HEAD";
+        written += response.write(&mut writer, head).await?;
+        if !keep_alive {
+            break;
+        }
+    }
+    writer.shutdown().await?;
+    Ok((read, written))
+}
+
+enum Content {
+    Empty,
+    Text(String),
+    /// A file and the length to send from its current position
+    File(File, u64),
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    content: Content,
+}
+
+impl Response {
+    fn new(status: u16) -> Self {
+        let mut response = Response {
+            status,
+            headers: vec![("Date", http_date(SystemTime::now()))],
+            content: Content::Empty,
+        };
+        if status >= 400 {
+            let text = format!("{} {}\n", status, http::reason(status));
+            response = response
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .text(text);
+        }
+        response
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn text(mut self, text: String) -> Self {
+        self.content = Content::Text(text);
+        self
+    }
+
+    fn close(self) -> Self {
+        self.header("Connection", "close")
+    }
+
+    async fn write<W>(self, writer: &mut W, head_only: bool) -> io::Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let length = match &self.content {
+            // a 304 has the length of the content it stands for, if any
+            Content::Empty if self.status == 304 => None,
+            Content::Empty => Some(0),
+            Content::Text(text) => Some(text.len() as u64),
+            Content::File(_, length) => Some(*length),
+        };
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, http::reason(self.status));
+        for (name, value) in &self.headers {
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        if let Some(length) = length {
+            let _ = write!(head, "Content-Length: {}\r\n", length);
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes()).await?;
+        let mut written = head.len() as u64;
+        if !head_only {
+            written += match self.content {
+                Content::Empty => 0,
+                Content::Text(text) => {
+                    writer.write_all(text.as_bytes()).await?;
+                    text.len() as u64
+                }
+                Content::File(file, length) => {
+                    let copied = io::copy(&mut file.take(length), writer).await?;
+                    if copied < length {
+                        // the file shrank, the response can't be completed
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    copied
+                }
+            };
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+}
+
+async fn respond(request: &Request, root: &Path, listing: bool) -> Response {
+    let method = request.method();
+    if method != b"GET" && method != b"HEAD" {
+        return Response::new(405).header("Allow", "GET, HEAD");
+    }
+    let Some((raw_path, query)) = request_path(request.target().0) else {
+        return Response::new(400);
+    };
+    let Some(path) = decode_path(raw_path) else {
+        return Response::new(400);
+    };
+    let Some(file) = resolve(root, &path).await else {
+        return Response::new(404);
+    };
+    let metadata = match fs::metadata(&file).await {
+        Ok(metadata) => metadata,
+        Err(e) => return error_response(&e),
+    };
+    if !metadata.is_dir() {
+        return file_response(request, &file, &metadata).await;
+    }
+    if !path.ends_with('/') {
+        // a single leading slash, `//host/` would send the client to another host
+        let raw_path = String::from_utf8_lossy(raw_path);
+        let mut location = format!("/{}/", raw_path.trim_start_matches('/'));
+        if let Some(query) = query {
+            location.push('?');
+            location.push_str(&String::from_utf8_lossy(query));
+        }
+        return Response::new(301).header("Location", location);
+    }
+    let index = file.join(INDEX);
+    if let Ok(metadata) = fs::metadata(&index).await {
+        if metadata.is_file() {
+            return file_response(request, &index, &metadata).await;
+        }
+    }
+    if !listing {
+        return Response::new(403);
+    }
+    match list(&file, &path).await {
+        Ok(page) => Response::new(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Cache-Control", "no-cache")
+            .text(page),
+        Err(e) => error_response(&e),
+    }
+}
+
+fn error_response(err: &io::Error) -> Response {
+    match err.kind() {
+        io::ErrorKind::NotFound => Response::new(404),
+        io::ErrorKind::PermissionDenied => Response::new(403),
+        _ => {
+            warn!("failed to serve file: {}", err);
+            Response::new(500)
+        }
+    }
+}
+
+async fn file_response(request: &Request, path: &Path, metadata: &std::fs::Metadata) -> Response {
+    let length = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = etag(length, modified);
+    let last_modified = modified.map(http_date);
+    let not_modified = match request.tokens("If-None-Match") {
+        tags if !tags.is_empty() => tags
+            .iter()
+            .any(|tag| tag == "*" || weak_eq(tag, &etag.to_ascii_lowercase())),
+        _ => match (modified, header(request, "If-Modified-Since")) {
+            (Some(modified), Some(since)) => {
+                parse_http_date(&since).is_some_and(|since| unix_secs(modified) <= since)
+            }
+            _ => false,
+        },
+    };
+    let mut response = Response::new(200);
+    if not_modified {
+        response.status = 304;
+    }
+    response = response.header("ETag", etag.clone());
+    if let Some(last_modified) = &last_modified {
+        response = response.header("Last-Modified", last_modified.clone());
+    }
+    if not_modified {
+        return response;
+    }
+    response = response
+        .header("Content-Type", mime_type(path))
+        .header("Accept-Ranges", "bytes");
+
+    let range = match header(request, "Range") {
+        Some(range) if if_range(request, &etag, last_modified.as_deref()) => {
+            parse_range(&range, length)
+        }
+        _ => None,
+    };
+    let (start, end) = match range {
+        None => (0, length),
+        Some(Ok((start, end))) => {
+            response.status = 206;
+            let range = format!("bytes {}-{}/{}", start, end - 1, length);
+            response = response.header("Content-Range", range);
+            (start, end)
+        }
+        Some(Err(())) => {
+            return Response::new(416).header("Content-Range", format!("bytes */{}", length));
+        }
+    };
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) => return error_response(&e),
+    };
+    if start > 0 {
+        if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+            return error_response(&e);
+        }
+    }
+    response.content = Content::File(file, end - start);
+    response
+}
+
+/// Whether a `Range` applies, `If-Range` must match the strong entity tag or
+/// the exact modification date.
+fn if_range(request: &Request, etag: &str, last_modified: Option<&str>) -> bool {
+    match header(request, "If-Range") {
+        None => true,
+        Some(value) if value.starts_with('"') => value == etag,
+        Some(value) => last_modified == Some(value.as_str()),
+    }
+}
+
+/// Parses a single byte range into `[start, end)`. `None` if the header is
+/// to be ignored, e.g. other units or several ranges, `Err` if it can't be
+/// satisfied.
+fn parse_range(value: &str, length: u64) -> Option<Result<(u64, u64), ()>> {
+    let range = value.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.trim().split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        let suffix = end.parse::<u64>().ok()?;
+        (length.saturating_sub(suffix), length)
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = if end.is_empty() {
+            length
+        } else {
+            let end = end.parse::<u64>().ok()?;
+            if end < start {
+                return None;
+            }
+            end.saturating_add(1).min(length)
+        };
+        (start, end)
+    };
+    if range.0 >= range.1 {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
+/// The path and query of an origin-form or absolute-form request target.
+fn request_path(target: &[u8]) -> Option<(&[u8], Option<&[u8]>)> {
+    let target = match target.iter().position(|&b| b == b'#') {
+        Some(i) => &target[..i],
+        None => target,
+    };
+    let target = if target.starts_with(b"/") {
+        target
+    } else {
+        let lower = target.to_ascii_lowercase();
+        let rest = lower
+            .strip_prefix(b"http://")
+            .or_else(|| lower.strip_prefix(b"https://"))?;
+        let authority = rest.iter().position(|&b| b == b'/' || b == b'?');
+        let start = target.len() - rest.len() + authority.unwrap_or(rest.len());
+        if target.get(start) != Some(&b'/') {
+            return Some((b"/", None));
+        }
+        &target[start..]
+    };
+    Some(match target.iter().position(|&b| b == b'?') {
+        Some(i) => (&target[..i], Some(&target[i + 1..])),
+        None => (target, None),
+    })
+}
+
+/// Percent-decodes a request path, `None` unless the result is UTF-8 without
+/// NUL or backslash.
+fn decode_path(path: &[u8]) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+        let hex = [*bytes.next()?, *bytes.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+    let decoded = String::from_utf8(decoded).ok()?;
+    if decoded.contains(['\0', '\\']) {
+        return None;
+    }
+    Some(decoded)
+}
+
+/// The file of a decoded request path below `root`, `None` if it does not
+/// exist or is outside the root.
+async fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = root.to_path_buf();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            // e.g. `C:` on Windows
+            segment if segment.contains(':') && cfg!(windows) => return None,
+            segment => file.push(segment),
+        }
+    }
+    let file = fs::canonicalize(&file).await.ok()?;
+    if !file.starts_with(root) {
+        debug!("{} is outside of {}", file.display(), root.display());
+        return None;
+    }
+    Some(file)
+}
+
+async fn list(dir: &Path, path: &str) -> io::Result<String> {
+    let mut entries = Vec::new();
+    let mut read_dir = fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let is_dir = entry.file_type().await.is_ok_and(|t| t.is_dir());
+        entries.push((name, is_dir));
+    }
+    entries.sort();
+    let path = escape_html(path);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n\
+        <body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        path
+    );
+    if path != "/" {
+        page.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        let _ = writeln!(
+            page,
+            "<li><a href=\"{}{}\">{}{}</a></li>",
+            encode_path_segment(&name),
+            slash,
+            escape_html(&name),
+            slash
+        );
+    }
+    page.push_str("</ul>\n</body>\n</html>\n");
+    Ok(page)
+}
+
+fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for &b in segment.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", b);
+        }
+    }
+    encoded
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn header(request: &Request, name: &str) -> Option<String> {
+    let value = request.get_all(name);
+    if value.is_empty() {
+        return None;
+    }
+    String::from_utf8(value).ok()
+}
+
+fn etag(length: u64, modified: Option<SystemTime>) -> String {
+    let nanos = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    format!("\"{:x}-{:x}\"", length, nanos)
+}
+
+/// Compares entity tags weakly, `tag` being lowercase like `Request::tokens`.
+fn weak_eq(tag: &str, etag: &str) -> bool {
+    tag.trim_start_matches("w/") == etag.trim_start_matches("w/")
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" | "log" | "md" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "yaml" | "yml" => "application/yaml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Formats an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> String {
+    let secs = unix_secs(time);
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let secs = secs % 86400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days + 4).rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Parses an IMF-fixdate into seconds since the epoch.
+fn parse_http_date(date: &str) -> Option<u64> {
+    let mut parts = date.split_ascii_whitespace().skip(1);
+    let day = parts.next()?.parse::<i64>().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|&m| m == month)? as i64 + 1;
+    let year = parts.next()?.parse::<i64>().ok()?;
+    let mut time = parts.next()?.split(':').map(|t| t.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if parts.next() != Some("GMT") || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// See <https://howardhinnant.github.io/date_algorithms.html>.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::block_on;
+
+    #[test]
+    fn test_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784111777)
+        );
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            parse_http_date("Tue, 29 Feb 2028 23:59:59 GMT"),
+            Some(1835481599)
+        );
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 100))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Ok((900, 1000))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 1000))));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some(Ok((990, 1000))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(Ok((0, 1000))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("bytes=5-1", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[test]
+    fn test_request_path() {
+        let path = |target: &str| {
+            request_path(target.as_bytes()).map(|(path, query)| {
+                let query = query.map(|q| String::from_utf8(q.to_vec()).unwrap());
+                (String::from_utf8(path.to_vec()).unwrap(), query)
+            })
+        };
+        assert_eq!(
+            path("/a/b?c=d"),
+            Some(("/a/b".to_owned(), Some("c=d".to_owned())))
+        );
+        assert_eq!(path("/a#b"), Some(("/a".to_owned(), None)));
+        assert_eq!(path("http://host/a/b"), Some(("/a/b".to_owned(), None)));
+        assert_eq!(path("HTTP://host"), Some(("/".to_owned(), None)));
+        assert_eq!(path("*"), None);
+        assert_eq!(path("host:443"), None);
+
+        assert_eq!(decode_path(b"/a%20b/%E4%B8%AD").as_deref(), Some("/a b/中"));
+        assert_eq!(decode_path(b"/a%2"), None);
+        assert_eq!(decode_path(b"/a%00"), None);
+        assert_eq!(decode_path(b"/a%5C..%5Cb"), None);
+        assert_eq!(decode_path(b"/%FF"), None);
+    }
+
+    #[test]
+    fn test_serve() {
+        let root = std::env::temp_dir().join(format!("gt-files-{}", std::process::id()));
+        let outside = root.with_extension("outside");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join("a.txt"), "hello world").unwrap();
+        std::fs::write(root.join("dir").join("<b>.json"), "{}").unwrap();
+        std::fs::write(&outside, "secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        let root = std::fs::canonicalize(&root).unwrap();
+
+        let requests = |input: &str, listing: bool| {
+            block_on(async {
+                let (mut client, mut server) = io::duplex(64 * 1024);
+                client.write_all(input.as_bytes()).await.unwrap();
+                client.shutdown().await.unwrap();
+                serve(&mut server, &root, listing).await.unwrap();
+                let mut output = String::new();
+                client.read_to_string(&mut output).await.unwrap();
+                output
+            })
+        };
+        let status = |output: &str| output[9..12].to_owned();
+
+        let output = requests("GET /a.txt HTTP/1.1\r\n\r\n", false);
+        assert_eq!(status(&output), "200");
+        assert!(output.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(output.ends_with("Content-Length: 11\r\n\r\nhello world"));
+        let etag = output
+            .lines()
+            .find_map(|l| l.strip_prefix("ETag: "))
+            .unwrap()
+            .to_owned();
+
+        let output = requests(
+            &format!(
+                "GET /a.txt HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n\
+                GET /a.txt HTTP/1.1\r\nRange: bytes=6-\r\n\r\n\
+                HEAD /a.txt HTTP/1.1\r\nConnection: close\r\n\r\n\
+                GET /a.txt HTTP/1.1\r\n\r\n",
+                etag
+            ),
+            false,
+        );
+        let responses = output.split("HTTP/1.1 ").skip(1).collect::<Vec<_>>();
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].starts_with("304"));
+        assert!(responses[1].starts_with("206"));
+        assert!(responses[1].contains("Content-Range: bytes 6-10/11\r\n"));
+        assert!(responses[1].ends_with("\r\n\r\nworld"));
+        assert!(responses[2].starts_with("200"));
+        assert!(responses[2].ends_with("Content-Length: 11\r\n\r\n"));
+
+        let output = requests("GET /a.txt HTTP/1.1\r\nRange: bytes=20-\r\n\r\n", false);
+        assert_eq!(status(&output), "416");
+        let output = requests("GET /dir HTTP/1.1\r\n\r\n", false);
+        assert_eq!(status(&output), "301");
+        assert!(output.contains("Location: /dir/\r\n"));
+        let output = requests("GET //dir?a=1 HTTP/1.1\r\n\r\n", false);
+        assert_eq!(status(&output), "301");
+        assert!(output.contains("Location: /dir/?a=1\r\n"));
+        assert_eq!(
+            status(&requests("GET /dir/ HTTP/1.1\r\n\r\n", false)),
+            "403"
+        );
+        let output = requests("GET /dir/ HTTP/1.1\r\n\r\n", true);
+        assert_eq!(status(&output), "200");
+        assert!(output.contains("<a href=\"%3Cb%3E.json\">&lt;b&gt;.json</a>"));
+        let output = requests("GET /dir/%3Cb%3E.json HTTP/1.1\r\n\r\n", false);
+        assert!(output.contains("Content-Type: application/json\r\n"));
+
+        for target in [
+            "/../a.txt",
+            "/dir/../../a.txt",
+            "/%2E%2E/a.txt",
+            "/link",
+            "/nope",
+        ] {
+            let output = requests(&format!("GET {} HTTP/1.1\r\n\r\n", target), false);
+            assert_eq!(status(&output), "404", "{}", target);
+        }
+        let output = requests("POST /a.txt HTTP/1.1\r\nContent-Length: 1\r\n\r\nx", false);
+        assert_eq!(status(&output), "405");
+        assert_eq!(
+            status(&requests("GET a.txt HTTP/1.1\r\n\r\n", false)),
+            "400"
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_file(&outside).unwrap();
+    }
+}
@@ -0,0 +1,631 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! HTTP/1.1 aware bridging of http routes.
+//!
+//! Only the request heads sent over the data channel are parsed and rewritten,
+//! request bodies are streamed as they come, framed by `Content-Length` or
+//! chunked encoding, and responses are copied untouched. Requests follow each
+//! other on the same channel, and once a request asks for an upgrade, e.g.
+//! WebSocket, or is a `CONNECT`, the rest of the channel is copied raw.
+//!
+//! Http route channels that fail before reaching their target are answered
+//! with an error response whatever the mode, see `error_response`.
+
+use std::net::IpAddr;
+
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+use url::Url;
+
+use crate::peer::{ConnectOptions, LibError};
+
+const MAX_HEAD_LENGTH: u64 = 64 * 1024;
+const MAX_LINE_LENGTH: u64 = 4 * 1024;
+
+/// How the requests of a data channel are rewritten.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Rewrite {
+    /// Replaces the Host header
+    host: Option<String>,
+    /// Add X-Forwarded-For and X-Forwarded-Proto
+    forwarded: bool,
+    /// The X-Forwarded-Proto configured, unset if none
+    proto: Option<String>,
+    /// The address of the peer, appended to X-Forwarded-For
+    remote: Option<IpAddr>,
+}
+
+impl Rewrite {
+    /// The rewrite of the requests to `url`, `None` if the options ask for none
+    /// and the channel is bridged as a byte pipe.
+    pub(crate) fn new(url: &Url, options: &ConnectOptions, remote: Option<IpAddr>) -> Option<Self> {
+        if !options.use_local_as_http_host && !options.forwarded_headers {
+            return None;
+        }
+        let host = if options.use_local_as_http_host {
+            url.host_str().map(|host| match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_owned(),
+            })
+        } else {
+            None
+        };
+        let proto = Some(options.forwarded_proto.clone()).filter(|proto| !proto.is_empty());
+        Some(Rewrite {
+            host,
+            forwarded: options.forwarded_headers,
+            proto,
+            remote,
+        })
+    }
+
+    fn apply(&self, request: &mut Request) {
+        if let Some(host) = &self.host {
+            request.set("Host", host.as_bytes().to_vec());
+        }
+        if !self.forwarded {
+            return;
+        }
+        if let Some(remote) = self.remote {
+            let mut value = request.get_all("X-Forwarded-For");
+            if !value.is_empty() {
+                value.extend_from_slice(b", ");
+            }
+            value.extend_from_slice(remote.to_string().as_bytes());
+            request.set("X-Forwarded-For", value);
+        }
+        if let Some(proto) = &self.proto {
+            if request.get_all("X-Forwarded-Proto").is_empty() {
+                request.set("X-Forwarded-Proto", proto.as_bytes().to_vec());
+            }
+        }
+    }
+}
+
+/// Copies data between the channel and the target like `io::copy_bidirectional`,
+/// rewriting the requests sent to the target.
+pub(crate) async fn proxy<C, S>(
+    channel: &mut C,
+    target: &mut S,
+    rewrite: &Rewrite,
+) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (channel_read, mut channel_write) = io::split(channel);
+    let (mut target_read, mut target_write) = io::split(target);
+    let requests = async {
+        let mut reader = BufReader::new(channel_read);
+        let n = forward_requests(&mut reader, &mut target_write, rewrite).await?;
+        target_write.shutdown().await?;
+        Ok::<_, io::Error>(n)
+    };
+    let responses = async {
+        let n = io::copy(&mut target_read, &mut channel_write).await?;
+        channel_write.shutdown().await?;
+        Ok::<_, io::Error>(n)
+    };
+    tokio::try_join!(requests, responses)
+}
+
+/// The status of the response to an http route request whose channel failed
+/// before reaching the target, `None` for other failures.
+pub(crate) fn error_status(err: &anyhow::Error) -> Option<u16> {
+    match err.downcast_ref::<LibError>()? {
+        LibError::InvalidLabel(_) => Some(400),
+        LibError::NoRoute(_) => Some(404),
+        LibError::TargetConnectFailed(_) => Some(502),
+        LibError::TargetConnectTimeout(_) => Some(504),
+        _ => None,
+    }
+}
+
+/// Builds a `Connection: close` error response, the body rendered from the
+/// HTML `template` if any.
+pub(crate) fn error_response(status: u16, template: Option<&str>) -> Vec<u8> {
+    let reason = reason(status);
+    let (content_type, body) = match template {
+        Some(template) => (
+            "text/html; charset=utf-8",
+            template
+                .replace("{{status}}", &status.to_string())
+                .replace("{{reason}}", reason),
+        ),
+        None => (
+            "text/plain; charset=utf-8",
+            format!("{} {}\n", status, reason),
+        ),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+        Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body.as_bytes());
+    response
+}
+
+pub(crate) fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    }
+}
+
+async fn forward_requests<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    rewrite: &Rewrite,
+) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut written = 0;
+    loop {
+        let head = match read_head(reader).await? {
+            Some(head) => head,
+            None => return Ok(written),
+        };
+        let mut request = Request::parse(&head)?;
+        rewrite.apply(&mut request);
+        let head = request.to_bytes();
+        writer.write_all(&head).await?;
+        written += head.len() as u64;
+        written += match request.body()? {
+            Body::None => 0,
+            Body::Length(n) => copy_exact(reader, writer, n).await?,
+            Body::Chunked => copy_chunked(reader, writer).await?,
+        };
+        if request.is_upgrade() {
+            return Ok(written + io::copy_buf(reader, writer).await?);
+        }
+    }
+}
+
+/// Reads the lines of a request head up to the empty line, skipping empty
+/// lines before it. `None` if the channel ends between requests.
+pub(crate) async fn read_head<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        let limit = MAX_HEAD_LENGTH.saturating_sub(start as u64);
+        let n = (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', &mut head)
+            .await?;
+        if n == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            if start as u64 >= MAX_HEAD_LENGTH {
+                return Err(invalid_data("request head too large"));
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !head.ends_with(b"\n") {
+            if head.len() as u64 >= MAX_HEAD_LENGTH {
+                return Err(invalid_data("request head too large"));
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if is_empty_line(&head[start..]) {
+            if start == 0 {
+                head.clear();
+                continue;
+            }
+            return Ok(Some(head));
+        }
+    }
+}
+
+async fn read_line<R>(reader: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_LINE_LENGTH)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if !line.ends_with(b"\n") {
+        if line.len() as u64 >= MAX_LINE_LENGTH {
+            return Err(invalid_data("chunk line too long"));
+        }
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line)
+}
+
+pub(crate) async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, n: u64) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = io::copy_buf(&mut (&mut *reader).take(n), writer).await?;
+    if copied < n {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(copied)
+}
+
+/// Copies a chunked body as is, trailers included.
+pub(crate) async fn copy_chunked<R, W>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut written = 0;
+    loop {
+        let line = read_line(reader).await?;
+        writer.write_all(&line).await?;
+        written += line.len() as u64;
+        let size = chunk_size(&line)?;
+        if size == 0 {
+            break;
+        }
+        // the chunk data and its CRLF
+        written += copy_exact(reader, writer, size + 2).await?;
+    }
+    loop {
+        let line = read_line(reader).await?;
+        writer.write_all(&line).await?;
+        written += line.len() as u64;
+        if is_empty_line(&line) {
+            return Ok(written);
+        }
+    }
+}
+
+fn chunk_size(line: &[u8]) -> io::Result<u64> {
+    let size = line
+        .split(|&b| b == b';' || b == b'\r' || b == b'\n')
+        .next()
+        .unwrap_or_default();
+    std::str::from_utf8(size)
+        .ok()
+        .map(|size| size.trim_matches([' ', '\t']))
+        .filter(|size| !size.is_empty())
+        .and_then(|size| u64::from_str_radix(size, 16).ok())
+        .filter(|&size| size <= u64::MAX - 2)
+        .ok_or_else(|| invalid_data("invalid chunk size"))
+}
+
+fn trim(mut value: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = value {
+        value = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = value {
+        value = rest;
+    }
+    value
+}
+
+fn is_empty_line(line: &[u8]) -> bool {
+    line == b"\r\n" || line == b"\n"
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) enum Body {
+    None,
+    Length(u64),
+    Chunked,
+}
+
+/// A request head, header values are kept as bytes as they may not be UTF-8.
+#[derive(Debug)]
+pub(crate) struct Request {
+    line: Vec<u8>,
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Request {
+    pub(crate) fn parse(head: &[u8]) -> io::Result<Self> {
+        let mut lines = head
+            .split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .filter(|line| !line.is_empty());
+        let line = lines
+            .next()
+            .ok_or_else(|| invalid_data("empty request head"))?;
+        if line.split(|&b| b == b' ').count() != 3 {
+            return Err(invalid_data("invalid request line"));
+        }
+        let mut headers = Vec::new();
+        for line in lines {
+            let colon = line
+                .iter()
+                .position(|&b| b == b':')
+                .ok_or_else(|| invalid_data("invalid header"))?;
+            let name = std::str::from_utf8(&line[..colon])
+                .ok()
+                .filter(|name| {
+                    !name.is_empty() && !name.contains(|c: char| c.is_ascii_whitespace())
+                })
+                .ok_or_else(|| invalid_data("invalid header name"))?;
+            headers.push((name.to_owned(), trim(&line[colon + 1..]).to_vec()));
+        }
+        Ok(Request {
+            line: line.to_vec(),
+            headers,
+        })
+    }
+
+    pub(crate) fn method(&self) -> &[u8] {
+        self.line.split(|&b| b == b' ').next().unwrap_or_default()
+    }
+
+    /// The values of the header joined by commas, empty if it is missing.
+    pub(crate) fn get_all(&self, name: &str) -> Vec<u8> {
+        let values = self
+            .headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_slice())
+            .collect::<Vec<_>>();
+        values.join(b", ".as_slice())
+    }
+
+    /// Replaces all the values of the header, keeping the position of the first.
+    fn set(&mut self, name: &str, value: Vec<u8>) {
+        let position = self
+            .headers
+            .iter()
+            .position(|(n, _)| n.eq_ignore_ascii_case(name));
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        let position = position.unwrap_or(self.headers.len());
+        self.headers.insert(position, (name.to_owned(), value));
+    }
+
+    pub(crate) fn tokens(&self, name: &str) -> Vec<String> {
+        String::from_utf8_lossy(&self.get_all(name))
+            .split(',')
+            .map(|token| token.trim().to_ascii_lowercase())
+            .filter(|token| !token.is_empty())
+            .collect()
+    }
+
+    pub(crate) fn body(&self) -> io::Result<Body> {
+        let encodings = self.tokens("Transfer-Encoding");
+        let lengths = self.tokens("Content-Length");
+        // a request framed both ways smuggles another one past the target
+        if !encodings.is_empty() && !lengths.is_empty() {
+            return Err(invalid_data("both transfer encoding and content length"));
+        }
+        if let Some(last) = encodings.last() {
+            if last != "chunked" {
+                return Err(invalid_data("request body without chunked encoding"));
+            }
+            return Ok(Body::Chunked);
+        }
+        let Some(length) = lengths.first() else {
+            return Ok(Body::None);
+        };
+        if lengths.iter().any(|l| l != length) {
+            return Err(invalid_data("conflicting content lengths"));
+        }
+        length
+            .parse()
+            .map(Body::Length)
+            .map_err(|_| invalid_data("invalid content length"))
+    }
+
+    /// The request target and the HTTP version
+    pub(crate) fn target(&self) -> (&[u8], &[u8]) {
+        let mut parts = self.line.split(|&b| b == b' ').skip(1);
+        let target = parts.next().unwrap_or_default();
+        (target, parts.next().unwrap_or_default())
+    }
+
+    /// Whether the connection may carry another request after this one.
+    pub(crate) fn keep_alive(&self) -> bool {
+        let connection = self.tokens("Connection");
+        if connection.iter().any(|t| t == "close") {
+            return false;
+        }
+        self.target().1 != b"HTTP/1.0" || connection.iter().any(|t| t == "keep-alive")
+    }
+
+    fn is_upgrade(&self) -> bool {
+        self.method() == b"CONNECT"
+            || (self.tokens("Connection").iter().any(|t| t == "upgrade")
+                && !self.get_all("Upgrade").is_empty())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut head = self.line.clone();
+        head.extend_from_slice(b"\r\n");
+        for (name, value) in &self.headers {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::block_on;
+
+    fn forward(input: &[u8], rewrite: &Rewrite) -> io::Result<Vec<u8>> {
+        block_on(async {
+            let mut output = Vec::new();
+            let mut reader = BufReader::new(input);
+            let n = forward_requests(&mut reader, &mut output, rewrite).await?;
+            assert_eq!(n, output.len() as u64);
+            Ok(output)
+        })
+    }
+
+    fn rewrite() -> Rewrite {
+        let url = Url::parse("http://127.0.0.1:8080").unwrap();
+        let options = ConnectOptions {
+            use_local_as_http_host: true,
+            forwarded_headers: true,
+            forwarded_proto: "http".to_owned(),
+            ..Default::default()
+        };
+        Rewrite::new(&url, &options, Some("1.2.3.4".parse().unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_rewrite_new() {
+        let url = Url::parse("https://www.example.com/").unwrap();
+        assert_eq!(Rewrite::new(&url, &ConnectOptions::default(), None), None);
+        let options = ConnectOptions {
+            use_local_as_http_host: true,
+            ..Default::default()
+        };
+        let rewrite = Rewrite::new(&url, &options, None).unwrap();
+        assert_eq!(rewrite.host.as_deref(), Some("www.example.com"));
+        assert!(!rewrite.forwarded);
+        assert_eq!(rewrite.proto, None);
+
+        // the proto is configured, not taken from the target scheme
+        let options = ConnectOptions {
+            forwarded_headers: true,
+            forwarded_proto: "https".to_owned(),
+            ..Default::default()
+        };
+        for url in ["http://example.com", "unix:///run/app.sock"] {
+            let rewrite = Rewrite::new(&Url::parse(url).unwrap(), &options, None).unwrap();
+            assert_eq!(rewrite.proto.as_deref(), Some("https"));
+        }
+    }
+
+    #[test]
+    fn test_rewrite_head() {
+        let output = forward(
+            b"\r\nGET / HTTP/1.1\r\nhost: example.com\r\nAccept: */*\r\n\
+            x-forwarded-for: 10.0.0.1\r\n\r\n",
+            &rewrite(),
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            b"GET / HTTP/1.1\r\nHost: 127.0.0.1:8080\r\nAccept: */*\r\n\
+            X-Forwarded-For: 10.0.0.1, 1.2.3.4\r\nX-Forwarded-Proto: http\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_keep_alive_bodies() {
+        let input = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+            POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n0\r\nTrailer: x\r\n\r\n\
+            GET /c HTTP/1.1\r\n\r\n";
+        let output = forward(input, &Rewrite::default()).unwrap();
+        assert_eq!(output, input);
+
+        let output = forward(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel",
+            &Rewrite::default(),
+        );
+        assert_eq!(output.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let output = forward(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello",
+            &Rewrite::default(),
+        );
+        assert_eq!(output.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let output = forward(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+            &Rewrite::default(),
+        );
+        assert_eq!(output.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let output = forward(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n0\r\n\r\n",
+            &Rewrite::default(),
+        );
+        assert_eq!(output.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let frames = b"GET /b HTTP/1.1\r\nHost: example.com\r\n\r\n\x81\x00";
+        let mut input = b"GET /ws HTTP/1.1\r\nHost: example.com\r\n\
+            Connection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\r\n"
+            .to_vec();
+        input.extend_from_slice(frames);
+        let output = forward(&input, &rewrite()).unwrap();
+        assert!(output.starts_with(b"GET /ws HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n"));
+        assert!(output.ends_with(frames));
+    }
+
+    #[test]
+    fn test_error_response() {
+        let err = anyhow::anyhow!("refused").context(LibError::TargetConnectFailed("a".to_owned()));
+        assert_eq!(error_status(&err), Some(502));
+        let err = anyhow::Error::new(LibError::NoRoute("a".to_owned()));
+        assert_eq!(error_status(&err), Some(404));
+        let err =
+            anyhow::anyhow!("empty channel id").context(LibError::InvalidLabel("a".to_owned()));
+        assert_eq!(error_status(&err), Some(400));
+        assert_eq!(error_status(&anyhow::anyhow!("broken pipe")), None);
+
+        let response = error_response(404, None);
+        assert_eq!(
+            response,
+            b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\n\
+            Content-Length: 14\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n\
+            404 Not Found\n"
+        );
+        let response = error_response(504, Some("<h1>{{status}} {{reason}}</h1>"));
+        assert!(response.starts_with(b"HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert!(response.ends_with(b"\r\n\r\n<h1>504 Gateway Timeout</h1>"));
+    }
+
+    #[test]
+    fn test_invalid_head() {
+        for head in [
+            b"GET /\r\n\r\n".as_slice(),
+            b"GET / HTTP/1.1\r\nHost\r\n\r\n",
+            b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\n",
+        ] {
+            assert!(forward(head, &Rewrite::default()).is_err());
+        }
+    }
+}
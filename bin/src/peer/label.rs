@@ -0,0 +1,203 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+use url::form_urlencoded;
+
+use crate::peer::route::RouteKind;
+use crate::peer::ProxyProtocol;
+
+/// A data channel label, which selects the route of the channel.
+///
+/// ```text
+/// label  = [ route "/" ] id [ "?" params ]
+/// route  = [ kind ] name
+/// kind   = "@" / ":" / "~"      ; http, tcp or udp, http if omitted
+/// params = param *( "&" param ) ; form urlencoded
+/// param  = key [ "=" value ]
+/// ```
+///
+/// `name` is neither empty nor contains `/` or `?`, `id` is not empty and
+/// contains no `?`. A label without route selects the default http route, e.g.
+/// `uuid`, `@www/uuid`, `:22/uuid?proxy-protocol=v2` or `~53/uuid`.
+///
+/// The only param is `proxy-protocol=none|v1|v2`, replacing the one of the
+/// route options of http and tcp channels. Other params are rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Label {
+    pub(crate) kind: RouteKind,
+    /// `None` selects the default route
+    pub(crate) name: Option<String>,
+    pub(crate) id: String,
+    pub(crate) proxy_protocol: Option<ProxyProtocol>,
+}
+
+/// The label without its params, as channels are logged and reported.
+pub(crate) fn strip_params(label: &str) -> &str {
+    label.split_once('?').map_or(label, |(label, _)| label)
+}
+
+/// The route kind a label asks for, told by its first char even if it does not
+/// parse.
+pub(crate) fn kind_of(label: &str) -> RouteKind {
+    match strip_params(label).split_once('/') {
+        Some((route, _)) if route.starts_with(':') => RouteKind::Tcp,
+        Some((route, _)) if route.starts_with('~') => RouteKind::Udp,
+        _ => RouteKind::Http,
+    }
+}
+
+impl FromStr for Label {
+    type Err = Error;
+
+    fn from_str(label: &str) -> Result<Self> {
+        let (label, query) = match label.split_once('?') {
+            Some((label, query)) => (label, Some(query)),
+            None => (label, None),
+        };
+        let (route, id) = match label.split_once('/') {
+            Some((route, id)) => (Some(route), id),
+            None => (None, label),
+        };
+        if id.is_empty() {
+            bail!("empty channel id");
+        }
+        let (kind, name) = match route {
+            None => (RouteKind::Http, None),
+            Some(route) => {
+                let (kind, name) = match route.chars().next() {
+                    Some('@') => (RouteKind::Http, &route[1..]),
+                    Some(':') => (RouteKind::Tcp, &route[1..]),
+                    Some('~') => (RouteKind::Udp, &route[1..]),
+                    _ => (RouteKind::Http, route),
+                };
+                if name.is_empty() {
+                    bail!("empty route name");
+                }
+                (kind, Some(name.to_owned()))
+            }
+        };
+        let mut proxy_protocol = None;
+        for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match key.as_ref() {
+                "" => bail!("empty parameter name"),
+                "proxy-protocol" if proxy_protocol.is_some() => {
+                    bail!("duplicate parameter {}", key)
+                }
+                "proxy-protocol" if kind == RouteKind::Udp => {
+                    bail!("proxy-protocol on udp channel")
+                }
+                "proxy-protocol" => {
+                    proxy_protocol = Some(match value.as_ref() {
+                        "none" => ProxyProtocol::None,
+                        "v1" => ProxyProtocol::V1,
+                        "v2" => ProxyProtocol::V2,
+                        _ => bail!("invalid proxy-protocol {}", value),
+                    })
+                }
+                _ => bail!("unknown parameter {}", key),
+            }
+        }
+        Ok(Label {
+            kind,
+            name,
+            id: id.to_owned(),
+            proxy_protocol,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(
+        kind: RouteKind,
+        name: Option<&str>,
+        id: &str,
+        proxy_protocol: Option<ProxyProtocol>,
+    ) -> Label {
+        Label {
+            kind,
+            name: name.map(str::to_owned),
+            id: id.to_owned(),
+            proxy_protocol,
+        }
+    }
+
+    #[test]
+    fn test_parse_label() {
+        let cases = [
+            ("uuid", label(RouteKind::Http, None, "uuid", None)),
+            (
+                "www/uuid",
+                label(RouteKind::Http, Some("www"), "uuid", None),
+            ),
+            (
+                "@www/uuid",
+                label(RouteKind::Http, Some("www"), "uuid", None),
+            ),
+            (":22/uuid", label(RouteKind::Tcp, Some("22"), "uuid", None)),
+            ("~53/uuid", label(RouteKind::Udp, Some("53"), "uuid", None)),
+            ("@www/a/b", label(RouteKind::Http, Some("www"), "a/b", None)),
+            (
+                ":22/uuid?proxy-protocol=v2",
+                label(RouteKind::Tcp, Some("22"), "uuid", Some(ProxyProtocol::V2)),
+            ),
+            (
+                "uuid?proxy-protocol=none",
+                label(RouteKind::Http, None, "uuid", Some(ProxyProtocol::None)),
+            ),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(s.parse::<Label>().unwrap(), expected, "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_label() {
+        for s in [
+            "",
+            "/uuid",
+            "@/uuid",
+            ":/uuid",
+            "~/uuid",
+            "@www/",
+            "@www/?a=1",
+            "?a=1",
+            "uuid?=1",
+            "uuid?a=1",
+            "uuid?proxy-protocol=v3",
+            "uuid?proxy-protocol=v1&proxy-protocol=v2",
+            "~53/uuid?proxy-protocol=none",
+        ] {
+            assert!(s.parse::<Label>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_strip_params() {
+        assert_eq!(strip_params(":22/uuid?proxy-protocol=v2"), ":22/uuid");
+        assert_eq!(strip_params("uuid"), "uuid");
+        assert_eq!(kind_of(":22/uuid?x"), RouteKind::Tcp);
+        assert_eq!(kind_of("~/uuid"), RouteKind::Udp);
+        assert_eq!(kind_of(":uuid"), RouteKind::Http);
+        assert_eq!(kind_of("@/uuid"), RouteKind::Http);
+        assert_eq!(kind_of(":x?a=/b"), RouteKind::Http);
+    }
+}
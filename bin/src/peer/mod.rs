@@ -18,15 +18,59 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{bail, Context, Result};
 use log::*;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::io;
 use tokio::io::{stdin, stdout};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::timeout;
+use tokio::{io, select};
 
+use crate::framing;
+use crate::framing::Header;
+
+mod candidate;
 mod conn;
+mod connect;
+mod dns;
+mod files;
+mod http;
+mod label;
+mod mux;
+mod proxy;
+mod proxy_protocol;
+mod route;
+mod session;
+mod stats;
+mod tls;
+
+/// How many ops may wait for a session, the single session reads no further
+/// while its queue is full, a session among many gets `ErrorCode::Busy` instead
+const OP_CHANNEL_SIZE: usize = 16;
+
+/// Version of the signaling protocol, only bumped on breaking changes. Additive
+/// changes are advertised in `CAPABILITIES` instead.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const CAPABILITIES: &[&str] = &[
+    "sessions",
+    "stats",
+    "udp-routes",
+    "unix-routes",
+    "target-tls",
+    "ice-restart",
+    "non-trickle",
+    "chunked-framing",
+    "update-routes",
+    "pattern-routes",
+    "label-params",
+    "proxy-protocol",
+    "http-rewrite",
+    "file-routes",
+    "socks5-routes",
+    "http-connect-routes",
+    "errors",
+];
 
 pub fn start_peer_connection() {
     let rt = tokio::runtime::Builder::new_current_thread()
@@ -52,54 +96,526 @@ where
     R: io::AsyncReadExt + Unpin + Send + 'static,
     W: io::AsyncWriteExt + Unpin + Send + 'static,
 {
-    let handler = conn::PeerConnHandler::new(reader, writer).await?;
-    handler.handle().await
+    let reader = Arc::new(Mutex::new(reader));
+    let writer = Arc::new(Mutex::new(writer));
+    let mut op = read_first_op(&reader).await?;
+    let mut capabilities = Vec::new();
+    if let OP::Hello(hello) = &op.op {
+        handshake(hello, &OpWriter::new(Arc::clone(&writer), None)).await?;
+        capabilities = hello.capabilities.clone();
+        op = read_first_op(&reader).await?;
+    }
+    let capabilities = Arc::new(capabilities);
+    if op.session.is_some() {
+        return session::process(reader, writer, capabilities, op).await;
+    }
+    let op_writer = OpWriter::new(Arc::clone(&writer), None).with_capabilities(capabilities);
+    let config = match op.op {
+        OP::Config(config) => *config,
+        op => {
+            bail!("invalid config op {:?}", op);
+        }
+    };
+
+    let handler = conn::PeerConnHandler::new(config, op_writer.clone()).await?;
+    let (ops_tx, ops_rx) = mpsc::channel(OP_CHANNEL_SIZE);
+    let handle = handler.handle(ops_rx);
+    tokio::pin!(handle);
+    select! {
+        result = &mut handle => result,
+        _ = read_ops(reader, op_writer, ops_tx) => handle.await,
+    }
+}
+
+async fn read_first_op<R>(reader: &Arc<Mutex<R>>) -> Result<SessionOP>
+where
+    R: io::AsyncReadExt + Unpin,
+{
+    let json = timeout(Duration::from_secs(5), read_json(Arc::clone(reader)))
+        .await
+        .context("read config json timeout")?
+        .context("read config json")?;
+    debug!("config json: {}", &json);
+    serde_json::from_str::<SessionOP>(&json)
+        .with_context(|| format!("deserialize config json failed: {}", json))
+}
+
+/// Answers the `Hello` of the parent with ours, then refuses to go on if the
+/// protocol versions differ. Parents that never say hello are assumed to speak
+/// the current version.
+async fn handshake<W>(hello: &Hello, writer: &OpWriter<W>) -> Result<()>
+where
+    W: io::AsyncWriteExt + Unpin,
+{
+    info!(
+        "hello from parent, version {}, capabilities {:?}",
+        hello.version, hello.capabilities
+    );
+    writer
+        .write(OP::Hello(Hello::current()))
+        .await
+        .context("write hello")?;
+    if hello.version != PROTOCOL_VERSION {
+        let message = format!(
+            "incompatible protocol version {}, sidecar speaks {}",
+            hello.version, PROTOCOL_VERSION
+        );
+        writer
+            .write(OP::Error {
+                code: ErrorCode::IncompatibleVersion,
+                message: message.clone(),
+                channel: String::new(),
+            })
+            .await
+            .context("write error")?;
+        bail!(message);
+    }
+    Ok(())
+}
+
+/// Forwards the ops of a single session until the reader fails, the error is
+/// forwarded as well so that the handler can close the peer connection. Ops
+/// that can not be parsed are skipped, answered with `OP::Error` if the parent
+/// takes errors.
+async fn read_ops<R, W>(reader: Arc<Mutex<R>>, writer: OpWriter<W>, ops: mpsc::Sender<Result<OP>>)
+where
+    R: io::AsyncReadExt + Unpin,
+    W: io::AsyncWriteExt + Unpin,
+{
+    loop {
+        let json = match read_json(Arc::clone(&reader)).await {
+            Ok(json) => json,
+            Err(e) => {
+                let _ = ops.send(Err(e)).await;
+                return;
+            }
+        };
+        debug!("op json: {}", &json);
+        let op = match serde_json::from_str::<OP>(&json) {
+            Ok(op) => op,
+            Err(e) => {
+                error!("parse op json {}: {}", json, e);
+                if !writer.reports_errors() {
+                    continue;
+                }
+                let op = OP::Error {
+                    code: ErrorCode::UnknownOp,
+                    message: e.to_string(),
+                    channel: String::new(),
+                };
+                if let Err(e) = writer.write(op).await {
+                    error!("failed to write error: {}", e);
+                }
+                continue;
+            }
+        };
+        if ops.send(Ok(op)).await.is_err() {
+            return;
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(default, rename_all = "camelCase")]
 pub struct Config {
     pub stuns: Vec<String>,
+    pub ice_servers: Vec<IceServer>,
+    /// Only use relay candidates from the TURN servers, mainly for testing. Needs a `turn:` or
+    /// `turns:` url in `ice_servers`
+    pub relay_only: bool,
+    /// Routes by name. Keys starting with `^` are regexes and keys with `*` or `?`
+    /// are globs, their captures expand `$1` or `${name}` in the target. Captures
+    /// only hold letters, digits, `_` and `-`, and may not change the host or
+    /// port of the target. Http targets may also be `file:///path` directories
+    /// served as static files
     pub http_routes: HashMap<String, String>,
+    /// Tcp targets may also be `socks5://` or `http-connect://`, the peer then
+    /// asks for the target with a SOCKS5 or HTTP CONNECT request on the channel
     pub tcp_routes: HashMap<String, String>,
+    pub udp_routes: HashMap<String, String>,
+    /// How route targets are connected to
+    pub connect_options: ConnectOptions,
+    /// Connect options of http routes by route key, replacing `connect_options`
+    pub http_route_options: HashMap<String, ConnectOptions>,
+    /// Connect options of tcp routes by route key, replacing `connect_options`
+    pub tcp_route_options: HashMap<String, ConnectOptions>,
+    /// Connect options of udp routes by route key, replacing `connect_options`.
+    /// Only the bind options apply to udp, the tcp and http ones are rejected
+    pub udp_route_options: HashMap<String, ConnectOptions>,
     pub port_min: u16,
     pub port_max: u16,
+    /// Multiplex all sessions onto these UDP ports instead of allocating from `port_min..port_max`.
+    /// The muxes are shared within the process only, so this needs session mode, processes
+    /// serving one peer connection each would all bind the same ports
+    pub udp_mux_ports: Vec<u16>,
+    pub timeout: u16,
+    /// Restart ICE after the peer connection stays disconnected for this many seconds, 0 disables
+    /// it. Must be below the 25 seconds after which a disconnected connection fails, and only
+    /// applies if the parent said hello with `ice-restart` as the restart offer is sent unasked
+    pub ice_restart_timeout: u16,
+    /// Send `OfferSDP`/`AnswerSDP` once ICE gathering completes, with every candidate
+    /// embedded, instead of trickling `Candidate` ops
+    pub non_trickle: bool,
+    /// Seconds to wait for ICE gathering in non-trickle mode, 5 if 0
+    pub gathering_timeout: u16,
+    /// Network types to gather candidates on, `udp4` and `udp6` if empty. `tcp4` and `tcp6` are
+    /// rejected as this build has no ICE-TCP
+    pub network_types: Vec<NetworkType>,
+    /// Only gather candidates on these interfaces, a trailing `*` matches by prefix
+    pub interface_allow: Vec<String>,
+    /// Never gather candidates on these interfaces, a trailing `*` matches by prefix
+    pub interface_deny: Vec<String>,
+    /// Only gather candidates on IPs in these CIDRs
+    pub ip_include: Vec<String>,
+    /// Never gather candidates on IPs in these CIDRs, e.g. `fe80::/10`
+    pub ip_exclude: Vec<String>,
+    pub mdns_mode: Option<MdnsMode>,
+    /// Public IPs of a 1:1 NAT advertised directly, e.g. the elastic IP of a cloud VM.
+    /// `public/private` maps a single private IP when there are several
+    pub nat_1to1_ips: Vec<String>,
+    pub nat_1to1_candidate_type: Nat1To1CandidateType,
+    /// Static addresses of route target hosts, looked up before DNS,
+    /// e.g. `{"api.internal": ["10.0.0.2", "fd00::2"]}`
+    pub hosts: HashMap<String, Vec<String>>,
+    /// Seconds DNS answers of route target hosts are cached, 30 if 0. Every
+    /// session caches on its own
+    pub dns_cache_ttl: u16,
+    /// CIDRs that `socks5://` and `http-connect://` tcp route targets may connect
+    /// to on behalf of the peer, e.g. `10.0.0.0/8` or `fd00::1`, none if empty
+    pub proxy_allowed_cidrs: Vec<String>,
+    /// Ports those targets may connect to, e.g. `22` or `8000-8999`, none if empty
+    pub proxy_allowed_ports: Vec<String>,
+    /// Originate TLS towards `https`/`wss`/`tls` route targets instead of copying raw bytes,
+    /// `udp://` targets are never TLS
+    pub target_tls: bool,
+    /// The path to a PEM CA bundle used to verify route targets, system roots if empty
+    pub remote_cert: String,
+    /// Accept self-signed certs from route targets
+    pub remote_cert_insecure: bool,
+    /// The path to an HTML template of the responses to http route requests
+    /// that fail before reaching their target, `{{status}}` and `{{reason}}` are
+    /// replaced. A plain text response is sent if empty
+    pub http_error_page: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ConnectOptions {
+    /// Seconds to wait for a tcp connection, resolution included, 10 if 0
     pub timeout: u16,
+    /// Retries after a failed tcp connection
+    pub retries: u8,
+    /// Milliseconds before the first retry, doubled on every next one, 100 if 0
+    pub retry_backoff: u32,
+    /// Set TCP_NODELAY on tcp connections
+    pub no_delay: bool,
+    /// Seconds of idle time before and between TCP keepalive probes, 0 disables keepalive
+    pub keepalive: u16,
+    /// Local address to connect from, e.g. `10.0.0.1` or `[fd00::1]:0`
+    pub bind_address: String,
+    /// Local interface to connect through, Linux only
+    pub bind_interface: String,
+    /// PROXY protocol header sent to tcp and http route targets before any data,
+    /// also to the targets `socks5://` and `http-connect://` routes connect to
+    pub proxy_protocol: ProxyProtocol,
+    /// Rewrite the Host header of http route requests to the host of the target
+    pub use_local_as_http_host: bool,
+    /// Add X-Forwarded-For, and X-Forwarded-Proto if `forwarded_proto` is set, to http route
+    /// requests
+    pub forwarded_headers: bool,
+    /// The X-Forwarded-Proto of `forwarded_headers`, the scheme the client used to reach the
+    /// parent, e.g. `https`, not sent if empty
+    pub forwarded_proto: String,
+    /// List the directories without `index.html` of `file:///path` http route targets
+    pub directory_listing: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    #[default]
+    None,
+    /// Text header with the addresses only
+    V1,
+    /// Binary header with the addresses and the data channel label
+    V2,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkType {
+    Udp4,
+    Udp6,
+    Tcp4,
+    Tcp6,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MdnsMode {
+    /// Discard remote mDNS candidates and gather host candidates with IPs
+    Disabled,
+    /// Accept remote mDNS candidates and gather host candidates with IPs
+    QueryOnly,
+    /// Accept remote mDNS candidates and gather host candidates with mDNS names
+    QueryAndGather,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Nat1To1CandidateType {
+    /// Replace the private address of host candidates with the public one
+    #[default]
+    Host,
+    /// Advertise the public address as server reflexive candidates, keeping the host ones
+    Srflx,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct IceServer {
+    /// STUN/TURN urls, e.g. `turn:turn.example.com:3478?transport=udp` or `turns:turn.example.com:5349`
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+    /// `password` (default) or `oauth`, others are rejected
+    pub credential_type: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum OP {
-    Config(Config),
+    /// Optional first op of the parent, answered with the `Hello` of the sidecar
+    Hello(Hello),
+    Config(Box<Config>),
     OfferSDP(String),
     AnswerSDP(String),
     Candidate(String),
     GetOfferSDP {
         #[serde(rename = "channelName")]
         channel_name: String,
+        /// Overrides `Config::non_trickle` for this offer
+        #[serde(
+            default,
+            rename = "nonTrickle",
+            skip_serializing_if = "Option::is_none"
+        )]
+        non_trickle: Option<bool>,
+    },
+    /// Sends an `OfferSDP` with new ICE credentials, the answer is expected as `AnswerSDP`
+    RestartIce {},
+    GetStats {},
+    Stats(Stats),
+    /// Replaces the route tables for the data channels opened from now on
+    UpdateRoutes {
+        #[serde(default, rename = "httpRoutes")]
+        http_routes: HashMap<String, String>,
+        #[serde(default, rename = "tcpRoutes")]
+        tcp_routes: HashMap<String, String>,
+        #[serde(default, rename = "udpRoutes")]
+        udp_routes: HashMap<String, String>,
+        #[serde(default, rename = "httpRouteOptions")]
+        http_route_options: HashMap<String, ConnectOptions>,
+        #[serde(default, rename = "tcpRouteOptions")]
+        tcp_route_options: HashMap<String, ConnectOptions>,
+        #[serde(default, rename = "udpRouteOptions")]
+        udp_route_options: HashMap<String, ConnectOptions>,
+        /// Also close the open data channels whose route was removed or changed
+        #[serde(default, rename = "closeRemoved")]
+        close_removed: bool,
+    },
+    /// Tears down a session in multi-session mode, also sent back when a session ends
+    Close {
+        #[serde(default)]
+        reason: String,
+    },
+    /// Sent back when an op or a data channel fails, the session goes on unless it
+    /// is closed as well. Only sent in session mode or if the parent said hello with
+    /// `errors`, legacy parents read the ops strictly as replies to theirs
+    Error {
+        code: ErrorCode,
+        #[serde(default)]
+        message: String,
+        /// The label of the failed data channel, empty for session errors
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        channel: String,
     },
 }
 
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Hello {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub fn current() -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// The op is not known by this sidecar
+    UnknownOp,
+    /// The op is known but not expected here, e.g. `Config` twice
+    InvalidOp,
+    IncompatibleVersion,
+    /// No route matches the label of a data channel
+    NoRoute,
+    /// The route target can not be resolved, connected or handshaked with
+    TargetConnectFailed,
+    /// The route target did not accept the connection in time
+    TargetConnectTimeout,
+    /// The peer asked a dynamic route for a target out of the allow-list
+    TargetDenied,
+    /// The SDP can not be parsed or applied
+    InvalidSdp,
+    IceFailed,
+    /// The session idled without any data channel for too long
+    Timeout,
+    /// Too many ops are queued for the session, the op was dropped
+    Busy,
+    /// A route key is not a valid pattern, the routes are left as they were
+    InvalidRoutes,
+    /// The label of a data channel does not follow the label grammar
+    InvalidLabel,
+    /// Anything else, see the message
+    Internal,
+}
+
+/// Summary of the peer connection stats report, answers `OP::GetStats`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Stats {
+    pub selected_candidate_pair: Option<CandidatePairStats>,
+    pub sctp_state: String,
+    pub data_channels: Vec<DataChannelStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CandidatePairStats {
+    pub local: CandidateStats,
+    pub remote: CandidateStats,
+    /// In seconds
+    pub current_round_trip_time: f64,
+    /// In seconds
+    pub total_round_trip_time: f64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CandidateStats {
+    /// `host`, `srflx`, `prflx` or `relay`
+    pub candidate_type: String,
+    pub address: String,
+    pub port: u16,
+    /// `udp` or `tcp`
+    pub protocol: String,
+    /// The protocol between the peer and the TURN server for relay candidates
+    pub relay_protocol: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DataChannelStats {
+    pub label: String,
+    pub id: u16,
+    pub state: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+}
+
+/// An op tagged with the session it belongs to.
+///
+/// Old callers never send `session`, which keeps the sidecar in single-session
+/// mode. Once the first op carries a session id, every op must carry one and the
+/// sidecar hosts as many peer connections as there are sessions.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionOP {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    #[serde(flatten)]
+    pub op: OP,
+}
+
+pub(crate) struct OpWriter<W> {
+    writer: Arc<Mutex<W>>,
+    session: Option<String>,
+    /// The capabilities the parent said hello with, none if it never did
+    capabilities: Arc<Vec<String>>,
+}
+
+impl<W> Clone for OpWriter<W> {
+    fn clone(&self) -> Self {
+        OpWriter {
+            writer: Arc::clone(&self.writer),
+            session: self.session.clone(),
+            capabilities: Arc::clone(&self.capabilities),
+        }
+    }
+}
+
+impl<W> OpWriter<W>
+where
+    W: io::AsyncWriteExt + Unpin,
+{
+    pub(crate) fn new(writer: Arc<Mutex<W>>, session: Option<String>) -> Self {
+        OpWriter {
+            writer,
+            session,
+            capabilities: Default::default(),
+        }
+    }
+
+    pub(crate) fn with_capabilities(self, capabilities: Arc<Vec<String>>) -> Self {
+        OpWriter {
+            capabilities,
+            ..self
+        }
+    }
+
+    /// Whether the ops are written for a session, i.e. the process serves many peer connections.
+    pub(crate) fn in_session(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Whether errors may be reported with unasked `OP::Error`s.
+    pub(crate) fn reports_errors(&self) -> bool {
+        self.in_session() || self.supports("errors")
+    }
+
+    /// Whether the parent handles the ops of `capability` sent without being asked.
+    pub(crate) fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    pub(crate) async fn write(&self, op: OP) -> Result<()> {
+        let op = SessionOP {
+            session: self.session.clone(),
+            op,
+        };
+        let json = serde_json::to_string(&op).context("encode op")?;
+        write_json(Arc::clone(&self.writer), &json).await
+    }
+}
+
 pub async fn read_json<R>(reader: Arc<Mutex<R>>) -> Result<String>
 where
     R: io::AsyncReadExt + Unpin,
 {
-    const MAX_JSON_LENGTH: u32 = 8 * 1024;
-    let mut buffer = [0; 4];
     let mut reader = reader.lock().await;
-    reader
-        .read_exact(&mut buffer)
-        .await
-        .context("failed to receive header")?;
-    let length = u32::from_be_bytes(buffer);
-    if length > MAX_JSON_LENGTH {
-        return Err(anyhow!("json too large: {}", length));
-    }
-    let mut buffer = vec![0; length as usize];
-    reader
-        .read_exact(&mut buffer)
-        .await
-        .context("failed to receive json")?;
+    let buffer = framing::read_message(&mut *reader, Header::BigEndian).await?;
     let result = String::from_utf8(buffer).context("not utf8 json")?;
     Ok(result)
 }
@@ -109,46 +625,205 @@ where
     W: io::AsyncWriteExt + Unpin,
 {
     let mut writer = writer.lock().await;
-    let l = json.len() as u32;
-    writer
-        .write_all(&l.to_be_bytes())
-        .await
-        .context("write answer len")?;
-    writer
-        .write_all(json.as_bytes())
-        .await
-        .context("write answer")?;
-    writer.flush().await.context("flush answer")?;
-    Ok(())
+    framing::write_message(&mut *writer, Header::BigEndian, json.as_bytes()).await
 }
 
 #[derive(Error, Debug)]
 pub enum LibError {
     #[error("no channel in peer connection timeout")]
     NoChannelInPeerConnectionTimeout,
+    #[error("no route for {0}")]
+    NoRoute(String),
+    #[error("connect to {0} failed")]
+    TargetConnectFailed(String),
+    #[error("connect to {0} timed out")]
+    TargetConnectTimeout(String),
+    #[error("{0} is not allowed")]
+    TargetDenied(String),
+    #[error("invalid sdp")]
+    InvalidSdp,
+    #[error("ice failed")]
+    IceFailed,
+    #[error("invalid routes")]
+    InvalidRoutes,
+    #[error("invalid label {0}")]
+    InvalidLabel(String),
+}
+
+impl LibError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            LibError::NoChannelInPeerConnectionTimeout => ErrorCode::Timeout,
+            LibError::NoRoute(_) => ErrorCode::NoRoute,
+            LibError::TargetConnectFailed(_) => ErrorCode::TargetConnectFailed,
+            LibError::TargetConnectTimeout(_) => ErrorCode::TargetConnectTimeout,
+            LibError::TargetDenied(_) => ErrorCode::TargetDenied,
+            LibError::InvalidSdp => ErrorCode::InvalidSdp,
+            LibError::IceFailed => ErrorCode::IceFailed,
+            LibError::InvalidRoutes => ErrorCode::InvalidRoutes,
+            LibError::InvalidLabel(_) => ErrorCode::InvalidLabel,
+        }
+    }
+}
+
+/// Builds the `OP::Error` reporting `err`, coded after the `LibError` it carries.
+pub(crate) fn error_op(err: &anyhow::Error, channel: &str) -> OP {
+    let code = err
+        .downcast_ref::<LibError>()
+        .map_or(ErrorCode::Internal, LibError::code);
+    OP::Error {
+        code,
+        message: err.to_string(),
+        channel: channel.to_owned(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use anyhow::anyhow;
+    use tokio::io::duplex;
+
     use super::*;
+    use crate::test_util::block_on;
 
     #[ignore]
     #[test]
     fn test_op_json() {
-        let op = OP::Config(Config {
+        let op = OP::Config(Box::new(Config {
             stuns: vec!["stun:stun.l.google.com:19302".to_owned()],
             http_routes: HashMap::from([
                 ("www".to_owned(), "http://www.baidu.com".to_owned()),
                 ("default".to_owned(), "http://www.baidu.com".to_owned()),
             ]),
             ..Default::default()
-        });
+        }));
         println!("{}", serde_json::to_string(&op).unwrap());
         let op = OP::OfferSDP("abc".to_owned());
         println!("{}", serde_json::to_string(&op).unwrap());
         let op = OP::GetOfferSDP {
             channel_name: "abc".to_owned(),
+            non_trickle: None,
         };
         println!("{}", serde_json::to_string(&op).unwrap());
     }
+
+    #[test]
+    fn test_session_op_json() {
+        let op = SessionOP {
+            session: Some("1".to_owned()),
+            op: OP::OfferSDP("abc".to_owned()),
+        };
+        let json = serde_json::to_string(&op).unwrap();
+        assert_eq!(json, r#"{"session":"1","offerSDP":"abc"}"#);
+        let op = SessionOP {
+            session: None,
+            op: OP::OfferSDP("abc".to_owned()),
+        };
+        let json = serde_json::to_string(&op).unwrap();
+        assert_eq!(json, serde_json::to_string(&op.op).unwrap());
+
+        let op = serde_json::from_str::<SessionOP>(r#"{"config":{"timeout":10}}"#).unwrap();
+        assert!(op.session.is_none());
+        assert!(matches!(op.op, OP::Config(config) if config.timeout == 10));
+        let op = serde_json::from_str::<SessionOP>(r#"{"session":"2","close":{}}"#).unwrap();
+        assert_eq!(op.session.as_deref(), Some("2"));
+        assert!(matches!(op.op, OP::Close { .. }));
+    }
+
+    #[test]
+    fn test_hello_json() {
+        let op = serde_json::from_str::<SessionOP>(
+            r#"{"hello":{"version":1,"capabilities":["stats","non-trickle"]}}"#,
+        )
+        .unwrap();
+        let OP::Hello(hello) = op.op else {
+            panic!("not hello: {:?}", op.op);
+        };
+        assert_eq!(hello.version, 1);
+        assert_eq!(hello.capabilities, vec!["stats", "non-trickle"]);
+
+        let op = OP::Error {
+            code: ErrorCode::IncompatibleVersion,
+            message: "abc".to_owned(),
+            channel: String::new(),
+        };
+        let json = serde_json::to_string(&op).unwrap();
+        assert_eq!(
+            json,
+            r#"{"error":{"code":"incompatibleVersion","message":"abc"}}"#
+        );
+    }
+
+    #[test]
+    fn test_error_op() {
+        let err = anyhow!("Connection refused")
+            .context(LibError::TargetConnectFailed(
+                "tcp://127.0.0.1:1".to_owned(),
+            ))
+            .context("connect");
+        let OP::Error {
+            code,
+            message,
+            channel,
+        } = error_op(&err, ":1/uuid")
+        else {
+            panic!("not error");
+        };
+        assert_eq!(code, ErrorCode::TargetConnectFailed);
+        assert_eq!(message, "connect");
+        assert_eq!(channel, ":1/uuid");
+
+        let err = anyhow!("deadline has elapsed").context(LibError::TargetConnectTimeout(
+            "tcp://10.0.0.1:22".to_owned(),
+        ));
+        let OP::Error { code, .. } = error_op(&err, ":22/uuid") else {
+            panic!("not error");
+        };
+        assert_eq!(code, ErrorCode::TargetConnectTimeout);
+
+        let OP::Error { code, .. } = error_op(&anyhow!("abc"), "") else {
+            panic!("not error");
+        };
+        assert_eq!(code, ErrorCode::Internal);
+    }
+
+    #[test]
+    fn test_unknown_op_error() {
+        block_on(async {
+            for (capabilities, errors) in [(vec![], false), (vec!["errors".to_owned()], true)] {
+                let (parent_writer, reader) = duplex(1024);
+                let (writer, parent_reader) = duplex(1024);
+                let parent_writer = Arc::new(Mutex::new(parent_writer));
+                let writer = OpWriter::new(Arc::new(Mutex::new(writer)), None)
+                    .with_capabilities(Arc::new(capabilities));
+                let (ops_tx, mut ops_rx) = mpsc::channel(OP_CHANNEL_SIZE);
+                tokio::spawn(read_ops(Arc::new(Mutex::new(reader)), writer, ops_tx));
+
+                write_json(Arc::clone(&parent_writer), r#"{"nope":1}"#)
+                    .await
+                    .unwrap();
+                write_json(Arc::clone(&parent_writer), r#"{"offerSDP":"abc"}"#)
+                    .await
+                    .unwrap();
+                let op = ops_rx.recv().await.unwrap().unwrap();
+                assert!(matches!(op, OP::OfferSDP(sdp) if sdp == "abc"));
+
+                // the legacy parent reads nothing but the end of the stream
+                drop(parent_writer);
+                let json = read_json(Arc::new(Mutex::new(parent_reader))).await;
+                if errors {
+                    let op = serde_json::from_str::<OP>(&json.unwrap()).unwrap();
+                    assert!(matches!(
+                        op,
+                        OP::Error {
+                            code: ErrorCode::UnknownOp,
+                            ..
+                        }
+                    ));
+                } else {
+                    assert!(json.is_err(), "{:?}", json);
+                }
+            }
+        });
+    }
 }
@@ -0,0 +1,97 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+#[cfg(unix)]
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{Context, Result};
+use log::*;
+#[cfg(unix)]
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use webrtc::ice::udp_mux::{UDPMuxDefault, UDPMuxParams};
+
+/// UDP muxes shared by every session of the process, keyed by port.
+static MUXES: OnceLock<Mutex<HashMap<u16, Arc<UDPMuxDefault>>>> = OnceLock::new();
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the mux of one of the ports, sessions are spread over the ports in
+/// turn. The socket of a port is bound once and then shared by all sessions.
+pub(crate) fn get(ports: &[u16]) -> Result<Arc<UDPMuxDefault>> {
+    let port = ports[NEXT.fetch_add(1, Ordering::Relaxed) % ports.len()];
+    let mut muxes = MUXES.get_or_init(Default::default).lock().unwrap();
+    if let Some(mux) = muxes.get(&port) {
+        return Ok(Arc::clone(mux));
+    }
+    let socket = bind(port).with_context(|| format!("bind udp mux port {}", port))?;
+    socket
+        .set_nonblocking(true)
+        .context("set udp mux socket nonblocking")?;
+    let socket = UdpSocket::from_std(socket).context("register udp mux socket")?;
+    info!("udp mux listening on {}", port);
+    let mux = UDPMuxDefault::new(UDPMuxParams::new(socket));
+    muxes.insert(port, Arc::clone(&mux));
+    Ok(mux)
+}
+
+/// Binds the port for both IPv4 and IPv6 host candidates. webrtc-ice maps IPv4
+/// peers onto a dual-stack socket, but only on unix, elsewhere and on hosts
+/// without IPv6 the port is bound for IPv4 only.
+fn bind(port: u16) -> io::Result<std::net::UdpSocket> {
+    #[cfg(unix)]
+    match bind_dual_stack(port) {
+        Ok(socket) => return Ok(socket),
+        Err(e) => warn!(
+            "bind udp mux port {} on [::] failed, ipv4 only: {}",
+            port, e
+        ),
+    }
+    std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+}
+
+#[cfg(unix)]
+fn bind_dual_stack(port: u16) -> io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::block_on;
+
+    #[test]
+    fn test_shared_port() {
+        block_on(async {
+            let port = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let first = get(&[port]).unwrap();
+            let second = get(&[port]).unwrap();
+            assert!(Arc::ptr_eq(&first, &second));
+            assert!(bind(port).is_err(), "port {} not held by the mux", port);
+        });
+    }
+}
@@ -0,0 +1,541 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Dynamic tcp routes, `socks5://` and `http-connect://` targets let the peer
+//! pick the host and port to connect to with a SOCKS5 (RFC 1928, CONNECT
+//! without authentication) or an HTTP CONNECT request on the data channel.
+//!
+//! Only the addresses and ports of the allow-list of the config are connected
+//! to, host names are resolved first and every address is checked.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ipnet::IpNet;
+use log::*;
+use tokio::io::{
+    self, AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::peer::dns::Resolver;
+use crate::peer::http::{self, Request};
+use crate::peer::{candidate, connect, Config, ConnectOptions, LibError};
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0x00;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 0x01;
+const SOCKS_IPV4: u8 = 0x01;
+const SOCKS_DOMAIN: u8 = 0x03;
+const SOCKS_IPV6: u8 = 0x04;
+const SOCKS_SUCCEEDED: u8 = 0x00;
+const SOCKS_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS_NOT_ALLOWED: u8 = 0x02;
+const SOCKS_HOST_UNREACHABLE: u8 = 0x04;
+const SOCKS_CONNECTION_REFUSED: u8 = 0x05;
+const SOCKS_TTL_EXPIRED: u8 = 0x06;
+const SOCKS_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    Socks5,
+    HttpConnect,
+}
+
+impl Protocol {
+    pub(crate) fn from_scheme(scheme: &str) -> Option<Self> {
+        match scheme {
+            "socks5" => Some(Protocol::Socks5),
+            "http-connect" => Some(Protocol::HttpConnect),
+            _ => None,
+        }
+    }
+}
+
+/// The addresses dynamic routes may connect to, nothing if either list is empty.
+#[derive(Debug, Default)]
+pub(crate) struct AllowList {
+    cidrs: Vec<IpNet>,
+    ports: Vec<(u16, u16)>,
+}
+
+impl AllowList {
+    pub(crate) fn new(config: &Config) -> Result<Self> {
+        let cidrs = candidate::parse_cidrs(&config.proxy_allowed_cidrs)?;
+        let ports = config
+            .proxy_allowed_ports
+            .iter()
+            .map(|ports| parse_ports(ports))
+            .collect::<Result<_>>()?;
+        Ok(AllowList { cidrs, ports })
+    }
+
+    fn allows_port(&self, port: u16) -> bool {
+        self.ports
+            .iter()
+            .any(|&(start, end)| (start..=end).contains(&port))
+    }
+
+    fn allows(&self, addr: &SocketAddr) -> bool {
+        let ip = match addr.ip() {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            ip => ip,
+        };
+        self.allows_port(addr.port()) && self.cidrs.iter().any(|net| net.contains(&ip))
+    }
+}
+
+fn parse_ports(ports: &str) -> Result<(u16, u16)> {
+    let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+    let range = start
+        .trim()
+        .parse::<u16>()
+        .and_then(|start| Ok((start, end.trim().parse::<u16>()?)))
+        .with_context(|| format!("invalid port range: {}", ports))?;
+    if range.0 > range.1 {
+        bail!("invalid port range: {}", ports);
+    }
+    Ok(range)
+}
+
+/// How dynamic routes connect to the targets the peer asks for.
+pub(crate) struct Dialer<'a> {
+    pub(crate) resolver: &'a Resolver,
+    pub(crate) allow: &'a AllowList,
+    pub(crate) options: &'a ConnectOptions,
+    /// The PROXY protocol header written to the target once connected, empty
+    /// if none
+    pub(crate) header: &'a [u8],
+}
+
+impl Dialer<'_> {
+    async fn dial(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let name = match host.parse::<Ipv6Addr>() {
+            Ok(_) => format!("[{}]:{}", host, port),
+            Err(_) => format!("{}:{}", host, port),
+        };
+        if !self.allow.allows_port(port) {
+            return Err(LibError::TargetDenied(name).into());
+        }
+        let ips = match time::timeout(self.options.timeout(), self.resolver.resolve(host)).await {
+            Ok(result) => result,
+            Err(e) => Err(anyhow!(e).context(format!("resolve {}", host))),
+        }
+        .map_err(|e| connect::failed(e, &name))?;
+        let addrs = ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .filter(|addr| self.allow.allows(addr))
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(LibError::TargetDenied(name).into());
+        }
+        let mut stream = connect::tcp_addrs(&name, &addrs, self.options)
+            .await
+            .map_err(|e| connect::failed(e, &name))?;
+        if !self.header.is_empty() {
+            stream
+                .write_all(self.header)
+                .await
+                .context("write proxy protocol header")
+                .map_err(|e| connect::failed(e, &name))?;
+        }
+        Ok(stream)
+    }
+}
+
+/// Answers the SOCKS5 or HTTP CONNECT request on the channel, then copies data
+/// between the channel and the requested target like `io::copy_bidirectional`.
+pub(crate) async fn serve<C>(
+    channel: &mut C,
+    protocol: Protocol,
+    dialer: &Dialer<'_>,
+) -> Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = io::split(channel);
+    let mut reader = BufReader::new(reader);
+    let request = match protocol {
+        Protocol::Socks5 => socks5_request(&mut reader, &mut writer).await,
+        Protocol::HttpConnect => http_connect_request(&mut reader, &mut writer).await,
+    };
+    let (host, port) = match request {
+        Ok(target) => target,
+        Err(err) => {
+            // the error reply, if any, is the last the peer gets
+            let _ = writer.shutdown().await;
+            return Err(err);
+        }
+    };
+    let mut stream = match dialer.dial(&host, port).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            let reply = match protocol {
+                Protocol::Socks5 => {
+                    let unspecified = (Ipv4Addr::UNSPECIFIED, 0).into();
+                    socks5_reply(socks5_code(&err), unspecified)
+                }
+                Protocol::HttpConnect => http_error(http_status(&err)),
+            };
+            writer.write_all(&reply).await.context("write reply")?;
+            writer.shutdown().await.context("close channel")?;
+            return Err(err);
+        }
+    };
+    info!("proxy connected to {}:{}", host, port);
+    let reply = match protocol {
+        Protocol::Socks5 => {
+            let bound = stream.local_addr().context("local address")?;
+            socks5_reply(SOCKS_SUCCEEDED, bound)
+        }
+        Protocol::HttpConnect => b"HTTP/1.1 200 Connection Established\r\n\r\n".to_vec(),
+    };
+    writer.write_all(&reply).await.context("write reply")?;
+
+    let (mut target_reader, mut target_writer) = stream.split();
+    let requests = async {
+        let n = io::copy_buf(&mut reader, &mut target_writer).await?;
+        target_writer.shutdown().await?;
+        Ok::<_, io::Error>(n)
+    };
+    let responses = async {
+        let n = io::copy(&mut target_reader, &mut writer).await?;
+        writer.shutdown().await?;
+        Ok::<_, io::Error>(n)
+    };
+    Ok(tokio::try_join!(requests, responses)?)
+}
+
+/// Negotiates no authentication and reads the CONNECT request.
+async fn socks5_request<R, W>(reader: &mut R, writer: &mut W) -> Result<(String, u16)>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut header = [0; 2];
+    reader
+        .read_exact(&mut header)
+        .await
+        .context("read greeting")?;
+    if header[0] != SOCKS_VERSION {
+        bail!("unsupported socks version {}", header[0]);
+    }
+    let mut methods = vec![0; header[1] as usize];
+    reader
+        .read_exact(&mut methods)
+        .await
+        .context("read methods")?;
+    if !methods.contains(&SOCKS_NO_AUTH) {
+        let reply = [SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD];
+        writer.write_all(&reply).await.context("write reply")?;
+        bail!("no acceptable socks authentication method in {:?}", methods);
+    }
+    let reply = [SOCKS_VERSION, SOCKS_NO_AUTH];
+    writer.write_all(&reply).await.context("write reply")?;
+
+    let mut request = [0; 4];
+    reader
+        .read_exact(&mut request)
+        .await
+        .context("read request")?;
+    let [version, command, _, address_type] = request;
+    if version != SOCKS_VERSION {
+        bail!("unsupported socks version {}", version);
+    }
+    let host = match address_type {
+        SOCKS_IPV4 => {
+            let mut ip = [0; 4];
+            reader.read_exact(&mut ip).await.context("read address")?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        SOCKS_IPV6 => {
+            let mut ip = [0; 16];
+            reader.read_exact(&mut ip).await.context("read address")?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        SOCKS_DOMAIN => {
+            let mut domain = vec![0; reader.read_u8().await.context("read address")? as usize];
+            reader
+                .read_exact(&mut domain)
+                .await
+                .context("read address")?;
+            String::from_utf8(domain).unwrap_or_default()
+        }
+        _ => {
+            let unspecified = (Ipv4Addr::UNSPECIFIED, 0).into();
+            let reply = socks5_reply(SOCKS_ADDRESS_TYPE_NOT_SUPPORTED, unspecified);
+            writer.write_all(&reply).await.context("write reply")?;
+            bail!("unsupported socks address type {}", address_type);
+        }
+    };
+    let port = reader.read_u16().await.context("read port")?;
+    if command != SOCKS_CONNECT || host.is_empty() {
+        let code = if command != SOCKS_CONNECT {
+            SOCKS_COMMAND_NOT_SUPPORTED
+        } else {
+            SOCKS_GENERAL_FAILURE
+        };
+        let reply = socks5_reply(code, (Ipv4Addr::UNSPECIFIED, 0).into());
+        writer.write_all(&reply).await.context("write reply")?;
+        bail!("unsupported socks request {} to {:?}", command, host);
+    }
+    Ok((host, port))
+}
+
+fn socks5_reply(code: u8, addr: SocketAddr) -> Vec<u8> {
+    let mut reply = vec![SOCKS_VERSION, code, 0];
+    match addr {
+        SocketAddr::V4(addr) => {
+            reply.push(SOCKS_IPV4);
+            reply.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            reply.push(SOCKS_IPV6);
+            reply.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    reply.extend_from_slice(&addr.port().to_be_bytes());
+    reply
+}
+
+fn socks5_code(err: &anyhow::Error) -> u8 {
+    let refused = err.chain().any(|e| {
+        e.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::ConnectionRefused)
+    });
+    match err.downcast_ref::<LibError>() {
+        Some(LibError::TargetDenied(_)) => SOCKS_NOT_ALLOWED,
+        Some(LibError::TargetConnectTimeout(_)) => SOCKS_TTL_EXPIRED,
+        Some(LibError::TargetConnectFailed(_)) if refused => SOCKS_CONNECTION_REFUSED,
+        Some(LibError::TargetConnectFailed(_)) => SOCKS_HOST_UNREACHABLE,
+        _ => SOCKS_GENERAL_FAILURE,
+    }
+}
+
+/// Reads the CONNECT request, the authority of which is the target.
+async fn http_connect_request<R, W>(reader: &mut R, writer: &mut W) -> Result<(String, u16)>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let head = http::read_head(reader)
+        .await
+        .context("read request")?
+        .ok_or_else(|| anyhow!("no request"))?;
+    let (status, err) = match Request::parse(&head) {
+        Ok(request) if request.method() != b"CONNECT" => (405, anyhow!("not a CONNECT request")),
+        Ok(request) => {
+            let authority = String::from_utf8_lossy(request.target().0).into_owned();
+            match parse_authority(&authority) {
+                Some(target) => return Ok(target),
+                None => (400, anyhow!("invalid CONNECT authority {}", authority)),
+            }
+        }
+        Err(e) => (400, anyhow!(e).context("invalid request")),
+    };
+    writer
+        .write_all(&http_error(status))
+        .await
+        .context("write reply")?;
+    Err(err)
+}
+
+/// Splits `host:port` or `[ipv6]:port`.
+fn parse_authority(authority: &str) -> Option<(String, u16)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let port = port.parse::<u16>().ok().filter(|&port| port != 0)?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => {
+            let host = host.strip_suffix(']')?;
+            host.parse::<Ipv6Addr>().ok()?;
+            host
+        }
+        None if host.is_empty() || host.contains([':', '/', '@', '[', ']']) => return None,
+        None => host,
+    };
+    Some((host.to_owned(), port))
+}
+
+fn http_status(err: &anyhow::Error) -> u16 {
+    match err.downcast_ref::<LibError>() {
+        Some(LibError::TargetDenied(_)) => 403,
+        Some(LibError::TargetConnectTimeout(_)) => 504,
+        _ => 502,
+    }
+}
+
+fn http_error(status: u16) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {} {}\r\n", status, http::reason(status));
+    if status == 405 {
+        response.push_str("Allow: CONNECT\r\n");
+    }
+    response.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+    response.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::test_util::block_on;
+
+    fn allow_list(cidrs: &[&str], ports: &[&str]) -> Result<AllowList> {
+        AllowList::new(&Config {
+            proxy_allowed_cidrs: cidrs.iter().map(|s| s.to_string()).collect(),
+            proxy_allowed_ports: ports.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let allow = allow_list(&["10.0.0.0/8", "fd00::1"], &["22", "8000-8999"]).unwrap();
+        let allows = |addr: &str| allow.allows(&addr.parse().unwrap());
+        assert!(allows("10.1.2.3:22"));
+        assert!(allows("10.1.2.3:8080"));
+        assert!(allows("[::ffff:10.1.2.3]:22"));
+        assert!(allows("[fd00::1]:8999"));
+        assert!(!allows("10.1.2.3:80"));
+        assert!(!allows("11.1.2.3:22"));
+        assert!(!allows("[fd00::2]:22"));
+        assert!(!allow_list(&["10.0.0.0/8"], &[])
+            .unwrap()
+            .allows(&"10.0.0.1:22".parse().unwrap()));
+
+        assert!(allow_list(&["10.0.0.0/33"], &[]).is_err());
+        assert!(allow_list(&[], &["9000-8000"]).is_err());
+        assert!(allow_list(&[], &["http"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_authority() {
+        let parse = |authority: &str| parse_authority(authority);
+        assert_eq!(
+            parse("example.com:443"),
+            Some(("example.com".to_owned(), 443))
+        );
+        assert_eq!(parse("10.0.0.1:22"), Some(("10.0.0.1".to_owned(), 22)));
+        assert_eq!(parse("[fd00::1]:22"), Some(("fd00::1".to_owned(), 22)));
+        assert_eq!(parse("fd00::1:22"), None);
+        assert_eq!(parse("example.com"), None);
+        assert_eq!(parse("example.com:0"), None);
+        assert_eq!(parse(":22"), None);
+        assert_eq!(parse("[example.com]:22"), None);
+    }
+
+    #[test]
+    fn test_serve() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    tokio::spawn(async move {
+                        let (mut reader, mut writer) = stream.split();
+                        let _ = io::copy(&mut reader, &mut writer).await;
+                    });
+                }
+            });
+            let resolver = Resolver::new(&Config::default()).unwrap();
+            let allow = allow_list(&["127.0.0.1"], &[&port.to_string()]).unwrap();
+            let options = ConnectOptions::default();
+            let dialer = Dialer {
+                resolver: &resolver,
+                allow: &allow,
+                options: &options,
+                header: &[],
+            };
+            let request = |protocol: Protocol, request: Vec<u8>, expected: usize| {
+                let dialer = &dialer;
+                async move {
+                    let (mut client, mut channel) = io::duplex(1024);
+                    client.write_all(&request).await.unwrap();
+                    client.write_all(b"ping").await.unwrap();
+                    client.shutdown().await.unwrap();
+                    let result = serve(&mut channel, protocol, dialer).await;
+                    let mut reply = vec![0; expected];
+                    client.read_exact(&mut reply).await.unwrap();
+                    let mut rest = Vec::new();
+                    client.read_to_end(&mut rest).await.unwrap();
+                    (result, reply, rest)
+                }
+            };
+
+            let mut socks = vec![5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1];
+            socks.extend_from_slice(&port.to_be_bytes());
+            let (result, reply, rest) = request(Protocol::Socks5, socks.clone(), 12).await;
+            assert_eq!(result.unwrap().0, 4);
+            assert_eq!(reply[..6], [5, 0, 5, 0, 0, 1]);
+            assert_eq!(rest, b"ping");
+
+            socks[10] = 2;
+            let (result, reply, _) = request(Protocol::Socks5, socks, 12).await;
+            let err = result.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(LibError::TargetDenied(_))
+            ));
+            assert_eq!(reply[..4], [5, 0, 5, SOCKS_NOT_ALLOWED]);
+
+            let socks = vec![5, 1, 2];
+            let (result, reply, _) = request(Protocol::Socks5, socks, 2).await;
+            assert!(result.is_err());
+            assert_eq!(reply, [5, SOCKS_NO_ACCEPTABLE_METHOD]);
+
+            let connect = format!("CONNECT localhost:{} HTTP/1.1\r\n\r\n", port);
+            let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+            let (result, reply, rest) = request(
+                Protocol::HttpConnect,
+                connect.into_bytes(),
+                established.len(),
+            )
+            .await;
+            assert!(result.is_ok());
+            assert_eq!(reply, established);
+            assert_eq!(rest, b"ping");
+
+            let connect = b"CONNECT 127.0.0.1:1 HTTP/1.1\r\n\r\n".to_vec();
+            let (result, reply, _) = request(Protocol::HttpConnect, connect, 12).await;
+            assert!(result.is_err());
+            assert_eq!(reply, b"HTTP/1.1 403");
+
+            let get = b"GET / HTTP/1.1\r\n\r\n".to_vec();
+            let (result, reply, _) = request(Protocol::HttpConnect, get, 12).await;
+            assert!(result.is_err());
+            assert_eq!(reply, b"HTTP/1.1 405");
+
+            let dialer = Dialer {
+                header: b"PROXY UNKNOWN\r\n",
+                ..dialer
+            };
+            let (mut client, mut channel) = io::duplex(1024);
+            let connect = format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\nping", port);
+            client.write_all(connect.as_bytes()).await.unwrap();
+            client.shutdown().await.unwrap();
+            assert!(serve(&mut channel, Protocol::HttpConnect, &dialer)
+                .await
+                .is_ok());
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            assert!(output.ends_with(b"\r\n\r\nPROXY UNKNOWN\r\nping"));
+        });
+    }
+}
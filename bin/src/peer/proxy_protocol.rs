@@ -0,0 +1,170 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! PROXY protocol headers, see
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::peer::ProxyProtocol;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2 and the PROXY command
+const V2_VERSION_COMMAND: u8 = 0x21;
+const V2_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+/// The first custom TLV type, carries the data channel label
+const PP2_TYPE_LABEL: u8 = 0xe0;
+
+/// Builds the header of a data channel from the remote (source) and local
+/// (destination) addresses of the selected ICE candidate pair, `None` if they
+/// are unknown, e.g. mDNS candidates. Mixed families are sent as IPv6.
+pub(crate) fn header(
+    version: ProxyProtocol,
+    addrs: Option<(SocketAddr, SocketAddr)>,
+    label: &str,
+) -> Vec<u8> {
+    let addrs = addrs.map(|(source, destination)| {
+        if source.is_ipv4() == destination.is_ipv4() {
+            (source, destination)
+        } else {
+            (to_ipv6(source), to_ipv6(destination))
+        }
+    });
+    match version {
+        ProxyProtocol::None => Vec::new(),
+        ProxyProtocol::V1 => v1(addrs),
+        ProxyProtocol::V2 => v2(addrs, label),
+    }
+}
+
+fn v1(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let header = match addrs {
+        Some((source, destination)) => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source.is_ipv4() { "TCP4" } else { "TCP6" },
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        ),
+        None => "PROXY UNKNOWN\r\n".to_owned(),
+    };
+    header.into_bytes()
+}
+
+fn v2(addrs: Option<(SocketAddr, SocketAddr)>, label: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    let family = match addrs {
+        Some((SocketAddr::V4(source), SocketAddr::V4(destination))) => {
+            body.extend_from_slice(&source.ip().octets());
+            body.extend_from_slice(&destination.ip().octets());
+            body.extend_from_slice(&source.port().to_be_bytes());
+            body.extend_from_slice(&destination.port().to_be_bytes());
+            V2_TCP4
+        }
+        Some((SocketAddr::V6(source), SocketAddr::V6(destination))) => {
+            body.extend_from_slice(&source.ip().octets());
+            body.extend_from_slice(&destination.ip().octets());
+            body.extend_from_slice(&source.port().to_be_bytes());
+            body.extend_from_slice(&destination.port().to_be_bytes());
+            V2_TCP6
+        }
+        _ => V2_UNSPEC,
+    };
+    // the length of the whole body must fit in 16 bits
+    let mut label = label.as_bytes();
+    let max = u16::MAX as usize - body.len() - 3;
+    if label.len() > max {
+        label = &label[..max];
+    }
+    body.push(PP2_TYPE_LABEL);
+    body.extend_from_slice(&(label.len() as u16).to_be_bytes());
+    body.extend_from_slice(label);
+
+    let mut header = Vec::with_capacity(16 + body.len());
+    header.extend_from_slice(V2_SIGNATURE);
+    header.push(V2_VERSION_COMMAND);
+    header.push(family);
+    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    header.extend_from_slice(&body);
+    header
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(source: &str, destination: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((source.parse().unwrap(), destination.parse().unwrap()))
+    }
+
+    #[test]
+    fn test_v1() {
+        let bytes = header(
+            ProxyProtocol::V1,
+            addrs("1.2.3.4:5000", "10.0.0.1:6000"),
+            "uuid",
+        );
+        assert_eq!(bytes, b"PROXY TCP4 1.2.3.4 10.0.0.1 5000 6000\r\n");
+
+        let bytes = header(
+            ProxyProtocol::V1,
+            addrs("1.2.3.4:5000", "[fd00::1]:6000"),
+            "uuid",
+        );
+        assert_eq!(bytes, b"PROXY TCP6 ::ffff:1.2.3.4 fd00::1 5000 6000\r\n");
+
+        let bytes = header(ProxyProtocol::V1, None, "uuid");
+        assert_eq!(bytes, b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn test_v2() {
+        let bytes = header(
+            ProxyProtocol::V2,
+            addrs("1.2.3.4:5000", "10.0.0.1:6000"),
+            ":22/uuid",
+        );
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 23]);
+        expected.extend_from_slice(&[1, 2, 3, 4, 10, 0, 0, 1, 0x13, 0x88, 0x17, 0x70]);
+        expected.extend_from_slice(&[0xe0, 0, 8]);
+        expected.extend_from_slice(b":22/uuid");
+        assert_eq!(bytes, expected);
+
+        let bytes = header(
+            ProxyProtocol::V2,
+            addrs("[fd00::1]:5000", "[fd00::2]:6000"),
+            "",
+        );
+        assert_eq!(bytes[13], V2_TCP6);
+        assert_eq!(bytes[14..16], [0, 39]);
+        assert_eq!(bytes.len(), 16 + 39);
+
+        let bytes = header(ProxyProtocol::V2, None, "uuid");
+        assert_eq!(bytes[13], V2_UNSPEC);
+        assert_eq!(bytes[14..], [0, 7, 0xe0, 0, 4, b'u', b'u', b'i', b'd']);
+    }
+}
@@ -0,0 +1,413 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use log::*;
+use regex::{Captures, Regex};
+use url::Url;
+
+use crate::peer::label::Label;
+use crate::peer::ConnectOptions;
+
+/// The http route of labels that match no other route
+const DEFAULT_ROUTE: &str = "@";
+/// What a glob capture matches, captures of regexes are held to it as well
+const CAPTURE: &str = "[A-Za-z0-9_-]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RouteKind {
+    Http,
+    Tcp,
+    Udp,
+}
+
+/// The target of a data channel and how to connect to it.
+#[derive(Debug, Clone)]
+pub(crate) struct Route {
+    pub(crate) target: String,
+    pub(crate) options: Arc<ConnectOptions>,
+}
+
+/// Connect options of routes by route key, `default` applies to routes without.
+#[derive(Debug, Default)]
+pub(crate) struct RouteOptions {
+    pub(crate) default: ConnectOptions,
+    pub(crate) http: HashMap<String, ConnectOptions>,
+    pub(crate) tcp: HashMap<String, ConnectOptions>,
+    pub(crate) udp: HashMap<String, ConnectOptions>,
+}
+
+/// The route tables of a session, replaced as a whole by `OP::UpdateRoutes`.
+#[derive(Debug, Default)]
+pub(crate) struct Routes {
+    http: RouteTable,
+    tcp: RouteTable,
+    udp: RouteTable,
+}
+
+impl Routes {
+    pub(crate) fn new(
+        http: HashMap<String, String>,
+        tcp: HashMap<String, String>,
+        udp: HashMap<String, String>,
+        options: RouteOptions,
+    ) -> Result<Self> {
+        options.default.validate().context("connect options")?;
+        for (key, options) in &options.udp {
+            options
+                .validate_udp()
+                .with_context(|| format!("connect options of udp route {}", key))?;
+        }
+        let default = Arc::new(options.default);
+        Ok(Routes {
+            http: RouteTable::new(http, options.http, &default).context("http routes")?,
+            tcp: RouteTable::new(tcp, options.tcp, &default).context("tcp routes")?,
+            udp: RouteTable::new(udp, options.udp, &default).context("udp routes")?,
+        })
+    }
+
+    /// Finds the route of a data channel label. Http names that match no
+    /// route fall back to the `@` route, as labels without route do. The params
+    /// of the label replace the route options.
+    pub(crate) fn route(&self, label: &Label) -> Option<Route> {
+        let name = label.name.as_deref();
+        let mut route = match label.kind {
+            RouteKind::Http => name
+                .and_then(|name| self.http.get(name))
+                .or_else(|| self.http.exact.get(DEFAULT_ROUTE).cloned()),
+            RouteKind::Tcp => self.tcp.get(name?),
+            RouteKind::Udp => self.udp.get(name?),
+        }?;
+        if let Some(proxy_protocol) = label.proxy_protocol {
+            Arc::make_mut(&mut route.options).proxy_protocol = proxy_protocol;
+        }
+        Some(route)
+    }
+}
+
+/// Route keys are matched exactly, except keys starting with `^` which are
+/// regexes and keys with `*` or `?` which are globs. An exact key always wins,
+/// then patterns are tried from the longest key to the shortest one, ties in
+/// key order. The captures of the matching pattern expand `$1` or `${name}` in
+/// the target, every `*` or `?` of a glob being a capture group. Captures may
+/// only hold letters, digits, `_` and `-`, so that the remote peer can not move
+/// the target to another host or port.
+#[derive(Debug, Default)]
+struct RouteTable {
+    exact: HashMap<String, Route>,
+    patterns: Vec<(String, Regex, Route)>,
+}
+
+impl RouteTable {
+    fn new(
+        routes: HashMap<String, String>,
+        mut options: HashMap<String, ConnectOptions>,
+        default: &Arc<ConnectOptions>,
+    ) -> Result<Self> {
+        let mut table = RouteTable::default();
+        for (key, target) in routes {
+            let options = match options.remove(&key) {
+                Some(options) => {
+                    options
+                        .validate()
+                        .with_context(|| format!("connect options of {}", key))?;
+                    Arc::new(options)
+                }
+                None => Arc::clone(default),
+            };
+            let target = Route { target, options };
+            let pattern = if key.starts_with('^') {
+                key.clone()
+            } else if key.contains(['*', '?']) {
+                glob_to_regex(&key)
+            } else {
+                table.exact.insert(key, target);
+                continue;
+            };
+            let regex =
+                Regex::new(&pattern).with_context(|| format!("invalid route pattern {}", key))?;
+            table.patterns.push((key, regex, target));
+        }
+        for key in options.keys() {
+            warn!("connect options of unknown route {}", key);
+        }
+        table
+            .patterns
+            .sort_by(|(a, ..), (b, ..)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        Ok(table)
+    }
+
+    fn get(&self, key: &str) -> Option<Route> {
+        if let Some(route) = self.exact.get(key) {
+            return Some(route.clone());
+        }
+        let (pattern, captures, route) = self
+            .patterns
+            .iter()
+            .find_map(|(pattern, regex, route)| Some((pattern, regex.captures(key)?, route)))?;
+        let Some(target) = expand(&captures, &route.target) else {
+            warn!("captures of {} rejected by route {}", key, pattern);
+            return None;
+        };
+        Some(Route {
+            target,
+            options: Arc::clone(&route.options),
+        })
+    }
+}
+
+/// Expands the captures into the target of a pattern route. `None` if a capture
+/// holds more than `CAPTURE`, or if the host or port of the expanded target are
+/// not the ones of the target, e.g. `tcp://host:2$1` expanded with
+/// `x@evil.com:22`.
+fn expand(captures: &Captures, target: &str) -> Option<String> {
+    let whole = target.contains("$0") || target.contains("${0}");
+    let safe = captures
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i > 0 || whole)
+        .filter_map(|(_, m)| m)
+        .all(|m| {
+            m.as_str()
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+        });
+    if !safe {
+        return None;
+    }
+    let mut expanded = String::new();
+    captures.expand(target, &mut expanded);
+    let url = Url::parse(&expanded).ok()?;
+
+    let (host, port) = host_and_port(target);
+    let mut expected_host = String::new();
+    captures.expand(host, &mut expected_host);
+    let mut expected_port = String::new();
+    captures.expand(port, &mut expected_port);
+    let port_matches = if expected_port.is_empty() {
+        url.port().is_none()
+    } else {
+        expected_port.parse().ok() == url.port_or_known_default()
+    };
+    let host_matches = url
+        .host_str()
+        .unwrap_or_default()
+        .eq_ignore_ascii_case(&expected_host);
+    (host_matches && port_matches).then_some(expanded)
+}
+
+/// Splits the host and port out of the authority of a target, which may still
+/// hold capture references.
+fn host_and_port(target: &str) -> (&str, &str) {
+    let Some((_, rest)) = target.split_once("://") else {
+        return ("", "");
+    };
+    let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, a)| a);
+    match authority.find(']') {
+        Some(end) if authority.starts_with('[') => {
+            let port = &authority[end + 1..];
+            (
+                &authority[..=end],
+                port.strip_prefix(':').unwrap_or_default(),
+            )
+        }
+        _ => authority.split_once(':').unwrap_or((authority, "")),
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(&format!("({}*)", CAPTURE)),
+            '?' => pattern.push_str(&format!("({})", CAPTURE)),
+            c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::ProxyProtocol;
+
+    #[test]
+    fn test_route() {
+        let routes = Routes::new(
+            HashMap::from([
+                ("@".to_owned(), "http://default".to_owned()),
+                ("www".to_owned(), "http://www".to_owned()),
+            ]),
+            HashMap::from([("22".to_owned(), "tcp://ssh".to_owned())]),
+            HashMap::from([("53".to_owned(), "udp://dns".to_owned())]),
+            RouteOptions::default(),
+        )
+        .unwrap();
+        let target = |label: &str| routes.route(&label.parse().unwrap()).map(|r| r.target);
+        assert_eq!(target("@www/uuid").as_deref(), Some("http://www"));
+        assert_eq!(target("www/uuid").as_deref(), Some("http://www"));
+        assert_eq!(target("uuid").as_deref(), Some("http://default"));
+        assert_eq!(target("@api/uuid").as_deref(), Some("http://default"));
+        assert_eq!(target(":22/uuid").as_deref(), Some("tcp://ssh"));
+        assert_eq!(target("~53/uuid").as_deref(), Some("udp://dns"));
+        assert_eq!(target(":23/uuid"), None);
+    }
+
+    #[test]
+    fn test_route_patterns() {
+        let routes = Routes::new(
+            HashMap::from([
+                ("@".to_owned(), "http://default".to_owned()),
+                ("tenant-a".to_owned(), "http://a".to_owned()),
+                ("tenant-*".to_owned(), "http://$1.tenants".to_owned()),
+                ("tenant-b*".to_owned(), "http://b.tenants/${1}".to_owned()),
+                (
+                    "^api-(?P<version>v[0-9]+)$".to_owned(),
+                    "http://api/${version}".to_owned(),
+                ),
+            ]),
+            HashMap::new(),
+            HashMap::new(),
+            RouteOptions::default(),
+        )
+        .unwrap();
+        let target = |label: &str| routes.route(&label.parse().unwrap()).map(|r| r.target);
+        assert_eq!(target("@tenant-a/uuid").as_deref(), Some("http://a"));
+        assert_eq!(
+            target("@tenant-c/uuid").as_deref(),
+            Some("http://c.tenants")
+        );
+        assert_eq!(
+            target("@tenant-b2/uuid").as_deref(),
+            Some("http://b.tenants/2")
+        );
+        assert_eq!(target("@api-v2/uuid").as_deref(), Some("http://api/v2"));
+        assert_eq!(target("@api-x/uuid").as_deref(), Some("http://default"));
+
+        let routes = HashMap::from([("^api-(".to_owned(), "http://api".to_owned())]);
+        let routes = Routes::new(
+            routes,
+            HashMap::new(),
+            HashMap::new(),
+            RouteOptions::default(),
+        );
+        assert!(routes.is_err());
+    }
+
+    #[test]
+    fn test_route_captures() {
+        let routes = Routes::new(
+            HashMap::new(),
+            HashMap::from([
+                ("2*".to_owned(), "tcp://host:2$1".to_owned()),
+                ("^x(.*)$".to_owned(), "tcp://host:2$1".to_owned()),
+                ("^db-(.*)$".to_owned(), "tcp://$1.internal:5432".to_owned()),
+                ("^v.*$".to_owned(), "tcp://$0.internal:22".to_owned()),
+            ]),
+            HashMap::new(),
+            RouteOptions::default(),
+        )
+        .unwrap();
+        let target = |label: &str| routes.route(&label.parse().unwrap()).map(|r| r.target);
+        assert_eq!(target(":23/id").as_deref(), Some("tcp://host:23"));
+        assert_eq!(target(":2x@evil.com:22/id"), None);
+        assert_eq!(target(":x2@evil.com:22/id"), None);
+        assert_eq!(
+            target(":db-a_1/id").as_deref(),
+            Some("tcp://a_1.internal:5432")
+        );
+        assert_eq!(target(":db-evil.com:22#/id"), None);
+        assert_eq!(target(":vm1/id").as_deref(), Some("tcp://vm1.internal:22"));
+        assert_eq!(target(":vm.evil.com/id"), None);
+
+        assert_eq!(host_and_port("tcp://host:2$1"), ("host", "2$1"));
+        assert_eq!(host_and_port("http://u@$1.tenants/x"), ("$1.tenants", ""));
+        assert_eq!(host_and_port("tcp://[::1]:${port}"), ("[::1]", "${port}"));
+        assert_eq!(host_and_port("unix:///run/$1.sock"), ("", ""));
+    }
+
+    #[test]
+    fn test_route_options() {
+        let options = RouteOptions {
+            default: ConnectOptions {
+                timeout: 5,
+                ..Default::default()
+            },
+            tcp: HashMap::from([(
+                "2*".to_owned(),
+                ConnectOptions {
+                    retries: 3,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let routes = Routes::new(
+            HashMap::from([("@".to_owned(), "http://default".to_owned())]),
+            HashMap::from([
+                ("22".to_owned(), "tcp://ssh".to_owned()),
+                ("2*".to_owned(), "tcp://host:2$1".to_owned()),
+            ]),
+            HashMap::new(),
+            options,
+        )
+        .unwrap();
+        let route = |label: &str| routes.route(&label.parse().unwrap()).unwrap();
+        assert_eq!(route("uuid").options.timeout, 5);
+        assert_eq!(
+            route(":22/uuid").options.proxy_protocol,
+            ProxyProtocol::None
+        );
+        let options = route(":22/uuid?proxy-protocol=v2").options;
+        assert_eq!(options.proxy_protocol, ProxyProtocol::V2);
+        assert_eq!(options.timeout, 5);
+        assert_eq!(route(":22/uuid").options.timeout, 5);
+        assert_eq!(route(":22/uuid").options.retries, 0);
+        let route = route(":23/uuid");
+        assert_eq!(route.target, "tcp://host:23");
+        assert_eq!(route.options.retries, 3);
+
+        let options = RouteOptions {
+            default: ConnectOptions {
+                bind_address: "eth0".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let routes = Routes::new(HashMap::new(), HashMap::new(), HashMap::new(), options);
+        assert!(routes.is_err());
+
+        let options = RouteOptions {
+            udp: HashMap::from([(
+                "53".to_owned(),
+                ConnectOptions {
+                    retries: 3,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let udp = HashMap::from([("53".to_owned(), "udp://dns".to_owned())]);
+        let routes = Routes::new(HashMap::new(), HashMap::new(), udp, options);
+        assert!(routes.is_err());
+    }
+}
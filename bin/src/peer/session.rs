@@ -0,0 +1,396 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use log::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::peer::conn::PeerConnHandler;
+use crate::peer::{read_json, Config, ErrorCode, OpWriter, SessionOP, OP, OP_CHANNEL_SIZE};
+
+/// Hosts many peer connections in one process. Every op carries a session id,
+/// `OP::Config` creates a session and `OP::Close` tears it down. A session also
+/// ends on its own when its peer connection fails or its idle timeout expires,
+/// in both cases `OP::Close` with the reason is sent back. The ops of a session
+/// are queued up to `OP_CHANNEL_SIZE`, past that they are answered with
+/// `ErrorCode::Busy` so that one stalled session never holds up the others.
+pub(crate) async fn process<R, W>(
+    reader: Arc<Mutex<R>>,
+    writer: Arc<Mutex<W>>,
+    capabilities: Arc<Vec<String>>,
+    first: SessionOP,
+) -> Result<()>
+where
+    R: AsyncReadExt + Unpin + Send + 'static,
+    W: AsyncWriteExt + Unpin + Send + 'static,
+{
+    serve(
+        reader,
+        writer,
+        capabilities,
+        first,
+        |config, writer, ops| async move {
+            let handler = PeerConnHandler::new(config, writer).await?;
+            Ok(handler.handle(ops))
+        },
+    )
+    .await
+}
+
+/// Dispatches the ops to the sessions `start` creates. `start` resolves once the
+/// session is created, to the future serving its ops until it ends.
+async fn serve<R, W, F, C, S>(
+    reader: Arc<Mutex<R>>,
+    writer: Arc<Mutex<W>>,
+    capabilities: Arc<Vec<String>>,
+    first: SessionOP,
+    start: F,
+) -> Result<()>
+where
+    R: AsyncReadExt + Unpin + Send + 'static,
+    W: AsyncWriteExt + Unpin + Send + 'static,
+    F: Fn(Config, OpWriter<W>, mpsc::Receiver<Result<OP>>) -> C,
+    C: Future<Output = Result<S>>,
+    S: Future<Output = Result<()>> + Send + 'static,
+{
+    let sessions: Arc<std::sync::Mutex<Sessions>> = Default::default();
+    let mut next = Some(first);
+    loop {
+        let op = match next.take() {
+            Some(op) => op,
+            None => {
+                let json = read_json(Arc::clone(&reader)).await?;
+                debug!("op json: {}", &json);
+                match serde_json::from_str::<SessionOP>(&json) {
+                    Ok(op) => op,
+                    Err(e) => {
+                        error!("parse op json {}: {}", json, e);
+                        let op = OP::Error {
+                            code: ErrorCode::UnknownOp,
+                            message: e.to_string(),
+                            channel: String::new(),
+                        };
+                        OpWriter::new(Arc::clone(&writer), session_of(&json))
+                            .write(op)
+                            .await
+                            .context("write error")?;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let Some(session) = op.session else {
+            error!("op without session: {:?}", op.op);
+            continue;
+        };
+        let op_writer = OpWriter::new(Arc::clone(&writer), Some(session.clone()))
+            .with_capabilities(Arc::clone(&capabilities));
+        match op.op {
+            OP::Config(config) => {
+                if sessions.lock().unwrap().contains_key(&session) {
+                    error!("session {} already exists", session);
+                    continue;
+                }
+                let (ops_tx, ops_rx) = mpsc::channel(OP_CHANNEL_SIZE);
+                let handle = match start(*config, op_writer.clone(), ops_rx).await {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        error!("create session {} err: {:?}", session, e);
+                        let reason = e.to_string();
+                        op_writer
+                            .write(OP::Close { reason })
+                            .await
+                            .context("write close")?;
+                        continue;
+                    }
+                };
+                info!("session {} created", session);
+                let id = sessions.lock().unwrap().insert(session.clone(), ops_tx);
+                let sessions = Arc::clone(&sessions);
+                tokio::spawn(async move {
+                    let reason = match handle.await {
+                        Ok(_) => {
+                            info!("session {} done", session);
+                            String::new()
+                        }
+                        Err(e) => {
+                            info!("session {} err: {:?}", session, e);
+                            e.to_string()
+                        }
+                    };
+                    sessions.lock().unwrap().remove(&session, id);
+                    if let Err(e) = op_writer.write(OP::Close { reason }).await {
+                        error!("failed to write close of session {}: {}", session, e);
+                    }
+                });
+            }
+            OP::Close { .. } => {
+                // dropping the sender ends the session, which reports back once closed
+                if sessions.lock().unwrap().take(&session).is_none() {
+                    warn!("close unknown session {}", session);
+                }
+            }
+            op => {
+                let ops = sessions.lock().unwrap().get(&session);
+                let Some(ops) = ops else {
+                    warn!("op for unknown session {}: {:?}", session, op);
+                    let reason = "unknown session".to_owned();
+                    op_writer
+                        .write(OP::Close { reason })
+                        .await
+                        .context("write close")?;
+                    continue;
+                };
+                match ops.try_send(Ok(op)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(op)) => {
+                        warn!("session {} is busy, drop {:?}", session, op);
+                        let op = OP::Error {
+                            code: ErrorCode::Busy,
+                            message: format!("{} ops queued", OP_CHANNEL_SIZE),
+                            channel: String::new(),
+                        };
+                        op_writer.write(op).await.context("write error")?;
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        debug!("session {} is closing", session);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The session id of an op that failed to parse, so that the error reaches the
+/// right session.
+fn session_of(json: &str) -> Option<String> {
+    let value = serde_json::from_str::<serde_json::Value>(json).ok()?;
+    value.get("session")?.as_str().map(str::to_owned)
+}
+
+/// Live sessions by id. Every session gets a unique sequence number so that a
+/// closing session never removes a newer one reusing its id.
+#[derive(Default)]
+struct Sessions {
+    seq: u64,
+    map: HashMap<String, (u64, mpsc::Sender<Result<OP>>)>,
+}
+
+impl Sessions {
+    fn contains_key(&self, session: &str) -> bool {
+        self.map.contains_key(session)
+    }
+
+    fn insert(&mut self, session: String, ops: mpsc::Sender<Result<OP>>) -> u64 {
+        self.seq += 1;
+        self.map.insert(session, (self.seq, ops));
+        self.seq
+    }
+
+    fn get(&self, session: &str) -> Option<mpsc::Sender<Result<OP>>> {
+        self.map.get(session).map(|(_, ops)| ops.clone())
+    }
+
+    fn take(&mut self, session: &str) -> Option<mpsc::Sender<Result<OP>>> {
+        self.map.remove(session).map(|(_, ops)| ops)
+    }
+
+    fn remove(&mut self, session: &str, id: u64) {
+        if matches!(self.map.get(session), Some((seq, _)) if *seq == id) {
+            self.map.remove(session);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+    use crate::peer::write_json;
+    use crate::test_util::block_on;
+
+    type Stream = Arc<Mutex<DuplexStream>>;
+
+    /// Starts the sessions as fakes answering every `OfferSDP` with the same
+    /// `AnswerSDP`, or never reading their ops if the config says `timeout: 1`.
+    fn spawn_serve(first: SessionOP) -> (Stream, Stream) {
+        let (parent_writer, reader) = duplex(64 * 1024);
+        let (writer, parent_reader) = duplex(64 * 1024);
+        let reader = Arc::new(Mutex::new(reader));
+        let writer = Arc::new(Mutex::new(writer));
+        tokio::spawn(serve(
+            reader,
+            writer,
+            Default::default(),
+            first,
+            |config, writer, mut ops| async move {
+                Ok(async move {
+                    if config.timeout == 1 {
+                        std::future::pending::<()>().await;
+                    }
+                    while let Some(op) = ops.recv().await {
+                        if let OP::OfferSDP(sdp) = op? {
+                            writer.write(OP::AnswerSDP(sdp)).await?;
+                        }
+                    }
+                    Ok(())
+                })
+            },
+        ));
+        (
+            Arc::new(Mutex::new(parent_writer)),
+            Arc::new(Mutex::new(parent_reader)),
+        )
+    }
+
+    fn op(session: &str, op: OP) -> SessionOP {
+        SessionOP {
+            session: Some(session.to_owned()),
+            op,
+        }
+    }
+
+    async fn send(writer: &Stream, session: &str, o: OP) {
+        let json = serde_json::to_string(&op(session, o)).unwrap();
+        write_json(Arc::clone(writer), &json).await.unwrap();
+    }
+
+    async fn recv(reader: &Stream) -> (String, OP) {
+        let json = read_json(Arc::clone(reader)).await.unwrap();
+        let op = serde_json::from_str::<SessionOP>(&json).unwrap();
+        (op.session.unwrap(), op.op)
+    }
+
+    fn offer(sdp: &str) -> OP {
+        OP::OfferSDP(sdp.to_owned())
+    }
+
+    fn answer(op: &OP) -> &str {
+        match op {
+            OP::AnswerSDP(sdp) => sdp,
+            op => panic!("not answer: {:?}", op),
+        }
+    }
+
+    #[test]
+    fn test_interleaved_sessions() {
+        block_on(async {
+            let (writer, reader) = spawn_serve(op("a", OP::Config(Box::default())));
+            send(&writer, "b", OP::Config(Box::default())).await;
+            for (session, sdp) in [("a", "1"), ("b", "2"), ("a", "3"), ("b", "4")] {
+                send(&writer, session, offer(sdp)).await;
+            }
+            let mut answers = HashMap::<String, Vec<String>>::new();
+            for _ in 0..4 {
+                let (session, op) = recv(&reader).await;
+                answers
+                    .entry(session)
+                    .or_default()
+                    .push(answer(&op).to_owned());
+            }
+            assert_eq!(answers["a"], ["1", "3"]);
+            assert_eq!(answers["b"], ["2", "4"]);
+        });
+    }
+
+    #[test]
+    fn test_unknown_session() {
+        block_on(async {
+            let (writer, reader) = spawn_serve(op("a", OP::Config(Box::default())));
+            send(&writer, "x", offer("1")).await;
+            let (session, op) = recv(&reader).await;
+            assert_eq!(session, "x");
+            assert!(matches!(op, OP::Close { reason } if reason == "unknown session"));
+
+            write_json(Arc::clone(&writer), r#"{"session":"a","nope":1}"#)
+                .await
+                .unwrap();
+            let (session, op) = recv(&reader).await;
+            assert_eq!(session, "a");
+            assert!(matches!(
+                op,
+                OP::Error {
+                    code: ErrorCode::UnknownOp,
+                    ..
+                }
+            ));
+        });
+    }
+
+    #[test]
+    fn test_close_one_session() {
+        block_on(async {
+            let (writer, reader) = spawn_serve(op("a", OP::Config(Box::default())));
+            send(&writer, "b", OP::Config(Box::default())).await;
+            send(
+                &writer,
+                "a",
+                OP::Close {
+                    reason: String::new(),
+                },
+            )
+            .await;
+            let (session, op) = recv(&reader).await;
+            assert_eq!(session, "a");
+            assert!(matches!(op, OP::Close { reason } if reason.is_empty()));
+
+            send(&writer, "b", offer("1")).await;
+            let (session, op) = recv(&reader).await;
+            assert_eq!(session, "b");
+            assert_eq!(answer(&op), "1");
+            send(&writer, "a", offer("2")).await;
+            let (session, op) = recv(&reader).await;
+            assert_eq!(session, "a");
+            assert!(matches!(op, OP::Close { reason } if reason == "unknown session"));
+        });
+    }
+
+    #[test]
+    fn test_busy_session() {
+        block_on(async {
+            let stalled = Config {
+                timeout: 1,
+                ..Default::default()
+            };
+            let (writer, reader) = spawn_serve(op("a", OP::Config(Box::new(stalled))));
+            send(&writer, "b", OP::Config(Box::default())).await;
+            for _ in 0..=OP_CHANNEL_SIZE {
+                send(&writer, "a", offer("1")).await;
+            }
+            let (session, op) = recv(&reader).await;
+            assert_eq!(session, "a");
+            assert!(matches!(
+                op,
+                OP::Error {
+                    code: ErrorCode::Busy,
+                    ..
+                }
+            ));
+            send(&writer, "b", offer("2")).await;
+            let (session, op) = recv(&reader).await;
+            assert_eq!(session, "b");
+            assert_eq!(answer(&op), "2");
+        });
+    }
+}
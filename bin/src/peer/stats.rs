@@ -0,0 +1,98 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::{IpAddr, SocketAddr};
+
+use webrtc::ice::candidate::CandidatePairState;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::stats::{ICECandidateStats, StatsReport, StatsReportType};
+
+use crate::peer::{CandidatePairStats, CandidateStats, DataChannelStats, Stats};
+
+/// Summarizes the stats report of the peer connection.
+pub(crate) async fn collect(pc: &RTCPeerConnection) -> Stats {
+    let report = pc.get_stats().await;
+    let mut data_channels = report
+        .reports
+        .values()
+        .filter_map(|r| match r {
+            StatsReportType::DataChannel(dc) => Some(DataChannelStats {
+                label: dc.label.clone(),
+                id: dc.data_channel_identifier,
+                state: dc.state.to_string(),
+                bytes_sent: dc.bytes_sent as u64,
+                bytes_received: dc.bytes_received as u64,
+                messages_sent: dc.messages_sent as u64,
+                messages_received: dc.messages_received as u64,
+            }),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    data_channels.sort_by_key(|dc| dc.id);
+    Stats {
+        selected_candidate_pair: selected_candidate_pair(&report),
+        sctp_state: pc.sctp().state().to_string(),
+        data_channels,
+    }
+}
+
+/// The remote and local addresses of the selected candidate pair, `None` before
+/// a pair is selected or if an address is not an IP, e.g. an mDNS name.
+pub(crate) async fn selected_addresses(pc: &RTCPeerConnection) -> Option<(SocketAddr, SocketAddr)> {
+    let pair = selected_candidate_pair(&pc.get_stats().await)?;
+    let addr = |c: &CandidateStats| {
+        let ip = c.address.parse::<IpAddr>().ok()?;
+        Some(SocketAddr::new(ip, c.port))
+    };
+    Some((addr(&pair.remote)?, addr(&pair.local)?))
+}
+
+fn selected_candidate_pair(report: &StatsReport) -> Option<CandidatePairStats> {
+    let pair = report
+        .reports
+        .values()
+        .filter_map(|r| match r {
+            StatsReportType::CandidatePair(pair) if pair.nominated => Some(pair),
+            _ => None,
+        })
+        .max_by_key(|pair| pair.state == CandidatePairState::Succeeded)?;
+    let local = match report.reports.get(&pair.local_candidate_id) {
+        Some(StatsReportType::LocalCandidate(c)) => candidate(c),
+        _ => return None,
+    };
+    let remote = match report.reports.get(&pair.remote_candidate_id) {
+        Some(StatsReportType::RemoteCandidate(c)) => candidate(c),
+        _ => return None,
+    };
+    Some(CandidatePairStats {
+        local,
+        remote,
+        current_round_trip_time: pair.current_round_trip_time,
+        total_round_trip_time: pair.total_round_trip_time,
+        bytes_sent: pair.bytes_sent,
+        bytes_received: pair.bytes_received,
+    })
+}
+
+fn candidate(c: &ICECandidateStats) -> CandidateStats {
+    CandidateStats {
+        candidate_type: c.candidate_type.to_string(),
+        address: c.ip.clone(),
+        port: c.port,
+        protocol: c.network_type.network_short(),
+        relay_protocol: c.relay_protocol.clone(),
+    }
+}
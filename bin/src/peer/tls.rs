@@ -0,0 +1,110 @@
+/*
+ * Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use url::{Host, Url};
+
+use crate::peer::Config;
+
+/// Route target schemes that are expected to speak TLS.
+pub(crate) fn is_tls_scheme(scheme: &str) -> bool {
+    matches!(scheme, "https" | "wss" | "tls")
+}
+
+/// Builds the connector used to originate TLS towards route targets, or `None`
+/// if `target_tls` is disabled.
+pub(crate) fn connector(config: &Config) -> Result<Option<TlsConnector>> {
+    if !config.target_tls {
+        return Ok(None);
+    }
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let tls_config = if config.remote_cert_insecure {
+        builder
+            .with_custom_certificate_verifier(Arc::new(InsecureVerifier))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        if config.remote_cert.is_empty() {
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+        } else {
+            let file = File::open(&config.remote_cert)
+                .with_context(|| format!("open remote cert {}", config.remote_cert))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                .with_context(|| format!("read remote cert {}", config.remote_cert))?;
+            let (valid, _) = roots.add_parsable_certificates(&certs);
+            if valid == 0 {
+                bail!("no valid certificate in {}", config.remote_cert);
+            }
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    Ok(Some(TlsConnector::from(Arc::new(tls_config))))
+}
+
+/// Performs the TLS handshake over `stream` with SNI taken from the url host.
+pub(crate) async fn connect<S>(
+    connector: &TlsConnector,
+    url: &Url,
+    stream: S,
+) -> Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = match url.host() {
+        Some(Host::Domain(domain)) => {
+            ServerName::try_from(domain).map_err(|_| anyhow!("invalid server name: {}", domain))?
+        }
+        Some(Host::Ipv4(ip)) => ServerName::IpAddress(ip.into()),
+        Some(Host::Ipv6(ip)) => ServerName::IpAddress(ip.into()),
+        None => bail!("no host in {}", url),
+    };
+    connector
+        .connect(name, stream)
+        .await
+        .context("tls handshake with service")
+}
+
+struct InsecureVerifier;
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
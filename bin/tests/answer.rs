@@ -72,6 +72,7 @@ fn answer_works() {
 
         let op = OP::GetOfferSDP {
             channel_name: "@www/uuid".to_owned(),
+            non_trickle: None,
         };
         write_json(
             Arc::clone(&writer),
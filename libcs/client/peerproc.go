@@ -15,7 +15,6 @@
 package client
 
 import (
-	"encoding/binary"
 	"encoding/json"
 	"errors"
 	"fmt"
@@ -24,6 +23,7 @@ import (
 	"github.com/isrc-cas/gt/client/std"
 	"github.com/isrc-cas/gt/pool"
 	"github.com/isrc-cas/gt/predef"
+	"github.com/isrc-cas/gt/util"
 	"github.com/rs/zerolog"
 	"io"
 	"net/http"
@@ -150,29 +150,12 @@ func (pt *peerProcessTask) init() (err error) {
 }
 
 func (pt *peerProcessTask) writeJson(json []byte) (err error) {
-	l := [4]byte{}
-	binary.BigEndian.PutUint32(l[:], uint32(len(json)))
-	_, err = pt.stdin.Write(l[:])
-	if err != nil {
-		return
-	}
-	_, err = pt.stdin.Write(json)
+	err = util.WriteFrames(pt.stdin, json)
 	return
 }
 
 func (pt *peerProcessTask) readJson() (json []byte, err error) {
-	l := [4]byte{}
-	_, err = pt.stdout.Read(l[:])
-	if err != nil {
-		return
-	}
-	jl := binary.BigEndian.Uint32(l[:])
-	if jl > 8*1024 {
-		err = errors.New("json too large")
-		return
-	}
-	json = make([]byte, jl)
-	_, err = io.ReadFull(pt.stdout, json)
+	json, err = util.ReadFrames(pt.stdout)
 	return
 }
 
@@ -40,36 +40,85 @@ const (
 	Reconnect            OPValue = "reconnect"
 )
 
+const (
+	// MaxFrameLength is the largest frame old peers accept, larger messages are
+	// split into frames of this size
+	MaxFrameLength = 8 * 1024
+
+	// MaxMessageLength is the largest message accepted, its frames added up
+	MaxMessageLength = 1024 * 1024
+
+	// continuation is set in the length of every frame of a message but the
+	// last one
+	continuation = 1 << 31
+)
+
 var writeMtx sync.Mutex
 
 func WriteJson(json []byte) (err error) {
 	writeMtx.Lock()
 	defer writeMtx.Unlock()
 
-	l := [4]byte{}
-	binary.BigEndian.PutUint32(l[:], uint32(len(json)))
-	_, err = os.Stdout.Write(l[:])
-	if err != nil {
-		return
-	}
-	_, err = os.Stdout.Write(json)
+	err = WriteFrames(os.Stdout, json)
 	return
 }
 
 func ReadJson() (json []byte, err error) {
+	json, err = ReadFrames(os.Stdin)
+	return
+}
+
+// WriteFrames writes json as frames of a 4-byte big-endian length followed by
+// that many bytes. Messages up to MaxFrameLength are a single frame, larger
+// ones are split into frames with the continuation bit set in the length of
+// all but the last one.
+func WriteFrames(w io.Writer, json []byte) (err error) {
 	l := [4]byte{}
-	_, err = os.Stdin.Read(l[:])
-	if err != nil {
-		return
+	for {
+		n := len(json)
+		length := uint32(n)
+		if n > MaxFrameLength {
+			n = MaxFrameLength
+			length = uint32(n) | continuation
+		}
+		binary.BigEndian.PutUint32(l[:], length)
+		_, err = w.Write(l[:])
+		if err != nil {
+			return
+		}
+		_, err = w.Write(json[:n])
+		if err != nil {
+			return
+		}
+		json = json[n:]
+		if len(json) == 0 {
+			return
+		}
 	}
-	jl := binary.BigEndian.Uint32(l[:])
-	if jl > 8*1024 {
-		err = errors.New("json too large")
-		return
+}
+
+// ReadFrames reads the frames of a message written by WriteFrames.
+func ReadFrames(r io.Reader) (json []byte, err error) {
+	l := [4]byte{}
+	for {
+		_, err = io.ReadFull(r, l[:])
+		if err != nil {
+			return
+		}
+		length := binary.BigEndian.Uint32(l[:])
+		more := length&continuation != 0
+		length &^= continuation
+		if uint64(len(json))+uint64(length) > MaxMessageLength {
+			err = errors.New("json too large")
+			return
+		}
+		start := len(json)
+		json = append(json, make([]byte, length)...)
+		_, err = io.ReadFull(r, json[start:])
+		if err != nil || !more {
+			return
+		}
 	}
-	json = make([]byte, jl)
-	_, err = io.ReadFull(os.Stdin, json)
-	return
 }
 
 func WriteOP(op OP) (err error) {
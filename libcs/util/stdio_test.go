@@ -0,0 +1,85 @@
+// Copyright (c) 2022 Institute of Software, Chinese Academy of Sciences (ISCAS)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+package util
+
+import (
+	"bytes"
+	"encoding/binary"
+	"os"
+	"strings"
+	"testing"
+)
+
+// testdata/frames.bin holds the frames of framesMessage, bin/src/framing.rs of
+// the sidecar checks the same file, so that both sides agree on the framing.
+var framesMessage = []byte(`{"offerSDP":"` + strings.Repeat("0123456789", 2000) + `"}`)
+
+func TestFramesCompatibleWithSidecar(t *testing.T) {
+	frames, err := os.ReadFile("testdata/frames.bin")
+	if err != nil {
+		t.Fatal(err)
+	}
+	json, err := ReadFrames(bytes.NewReader(frames))
+	if err != nil {
+		t.Fatal(err)
+	}
+	if !bytes.Equal(json, framesMessage) {
+		t.Fatalf("unexpected message of %d bytes", len(json))
+	}
+	var buf bytes.Buffer
+	err = WriteFrames(&buf, framesMessage)
+	if err != nil {
+		t.Fatal(err)
+	}
+	if !bytes.Equal(buf.Bytes(), frames) {
+		t.Fatalf("unexpected frames of %d bytes", buf.Len())
+	}
+}
+
+func TestSingleFrame(t *testing.T) {
+	tests := []struct {
+		json   string
+		frames string
+	}{
+		{json: "{}", frames: "\x00\x00\x00\x02{}"},
+		{json: "", frames: "\x00\x00\x00\x00"},
+	}
+	for _, test := range tests {
+		var buf bytes.Buffer
+		err := WriteFrames(&buf, []byte(test.json))
+		if err != nil {
+			t.Fatal(err)
+		}
+		if buf.String() != test.frames {
+			t.Fatalf("unexpected frames %q", buf.String())
+		}
+		json, err := ReadFrames(&buf)
+		if err != nil {
+			t.Fatal(err)
+		}
+		if string(json) != test.json {
+			t.Fatalf("unexpected message %q", json)
+		}
+	}
+}
+
+func TestMessageTooLarge(t *testing.T) {
+	l := [4]byte{}
+	binary.BigEndian.PutUint32(l[:], MaxMessageLength+1)
+	_, err := ReadFrames(bytes.NewReader(l[:]))
+	if err == nil {
+		t.Fatal("message over MaxMessageLength accepted")
+	}
+}